@@ -0,0 +1,9 @@
+DROP PROCEDURE IF EXISTS dbo.SP_LOGOUT;
+DROP PROCEDURE IF EXISTS dbo.SP_GET_USER_TOKEN;
+DROP PROCEDURE IF EXISTS dbo.SP_VALIDATE_TOKEN;
+DROP PROCEDURE IF EXISTS dbo.sp_usuarios_delete;
+DROP PROCEDURE IF EXISTS dbo.sp_usuarios_update;
+DROP PROCEDURE IF EXISTS dbo.sp_usuarios_insert;
+DROP TABLE IF EXISTS dbo.refreshtoken;
+DROP TABLE IF EXISTS dbo.usertoken;
+DROP TABLE IF EXISTS dbo.usuarios;
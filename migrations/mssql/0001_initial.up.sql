@@ -0,0 +1,171 @@
+-- Baseline schema. Every object is guarded so this can also be applied to a database
+-- that already has the tables and procedures; batches are separated by GO.
+-- Existing procedures are adopted as they are, never replaced: the later migrations that change them do so
+-- in steps of their own, which `migrate down` can undo.
+IF OBJECT_ID(N'dbo.usuarios', N'U') IS NULL
+CREATE TABLE dbo.usuarios (
+    codusr_usr     INT IDENTITY(1,1) PRIMARY KEY,
+    nombre_usr     NVARCHAR(100) NOT NULL CONSTRAINT UQ_usuarios_nombre_usr UNIQUE,
+    email_usr      NVARCHAR(150) NULL,
+    codperf_usr    INT NOT NULL,
+    contrasena_usr NVARCHAR(100) NOT NULL,
+    usercrea       INT NULL,
+    usermod        INT NULL,
+    fechcrea       DATETIME NULL,
+    fechmod        DATETIME NULL
+);
+GO
+
+IF OBJECT_ID(N'dbo.usertoken', N'U') IS NULL
+CREATE TABLE dbo.usertoken (
+    TokenID     INT IDENTITY(1,1) PRIMARY KEY,
+    UserID      INT NOT NULL,
+    token       VARCHAR(64) NOT NULL,
+    FamilyID    VARCHAR(36) NULL,
+    DeviceName  NVARCHAR(100) NULL,
+    UserAgent   NVARCHAR(400) NULL,
+    IpAddress   VARCHAR(45) NULL,
+    createdDate DATETIME NOT NULL,
+    LastSeen    DATETIME NULL,
+    expiredDate DATETIME NOT NULL,
+    expired     BIT NOT NULL DEFAULT 0,
+    usercrea    INT NULL,
+    usermod     INT NULL,
+    fechcrea    DATETIME NULL,
+    fechmod     DATETIME NULL
+);
+GO
+
+-- Older usertoken tables predate one-row-per-session
+IF COL_LENGTH(N'dbo.usertoken', N'TokenID') IS NULL ALTER TABLE dbo.usertoken ADD TokenID INT IDENTITY(1,1) NOT NULL;
+IF COL_LENGTH(N'dbo.usertoken', N'FamilyID') IS NULL ALTER TABLE dbo.usertoken ADD FamilyID VARCHAR(36) NULL;
+IF COL_LENGTH(N'dbo.usertoken', N'DeviceName') IS NULL ALTER TABLE dbo.usertoken ADD DeviceName NVARCHAR(100) NULL;
+IF COL_LENGTH(N'dbo.usertoken', N'UserAgent') IS NULL ALTER TABLE dbo.usertoken ADD UserAgent NVARCHAR(400) NULL;
+IF COL_LENGTH(N'dbo.usertoken', N'IpAddress') IS NULL ALTER TABLE dbo.usertoken ADD IpAddress VARCHAR(45) NULL;
+IF COL_LENGTH(N'dbo.usertoken', N'LastSeen') IS NULL ALTER TABLE dbo.usertoken ADD LastSeen DATETIME NULL;
+GO
+
+IF INDEXPROPERTY(OBJECT_ID(N'dbo.usertoken'), N'IX_usertoken_token', 'IndexID') IS NULL
+    CREATE INDEX IX_usertoken_token ON dbo.usertoken (token);
+IF INDEXPROPERTY(OBJECT_ID(N'dbo.usertoken'), N'IX_usertoken_family', 'IndexID') IS NULL
+    CREATE INDEX IX_usertoken_family ON dbo.usertoken (FamilyID);
+GO
+
+IF OBJECT_ID(N'dbo.refreshtoken', N'U') IS NULL
+CREATE TABLE dbo.refreshtoken (
+    RefreshID   INT IDENTITY(1,1) PRIMARY KEY,
+    UserID      INT NOT NULL,
+    FamilyID    VARCHAR(36) NOT NULL,
+    token       VARCHAR(64) NOT NULL,
+    createdDate DATETIME NOT NULL,
+    expiredDate DATETIME NOT NULL,
+    used        BIT NOT NULL DEFAULT 0,
+    revoked     BIT NOT NULL DEFAULT 0,
+    usercrea    INT NULL,
+    usermod     INT NULL,
+    fechcrea    DATETIME NULL,
+    fechmod     DATETIME NULL
+);
+GO
+
+IF INDEXPROPERTY(OBJECT_ID(N'dbo.refreshtoken'), N'IX_refreshtoken_token', 'IndexID') IS NULL
+    CREATE UNIQUE INDEX IX_refreshtoken_token ON dbo.refreshtoken (token);
+IF INDEXPROPERTY(OBJECT_ID(N'dbo.refreshtoken'), N'IX_refreshtoken_family', 'IndexID') IS NULL
+    CREATE INDEX IX_refreshtoken_family ON dbo.refreshtoken (FamilyID);
+GO
+
+IF OBJECT_ID(N'dbo.sp_usuarios_insert', N'P') IS NULL EXEC(N'
+CREATE PROCEDURE dbo.sp_usuarios_insert
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usercrea       INT,
+    @usermod        INT,
+    @fechcrea       DATETIME,
+    @fechmod        DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    INSERT INTO dbo.usuarios (nombre_usr, email_usr, codperf_usr, contrasena_usr, usercrea, usermod, fechcrea, fechmod)
+    VALUES (@nombre_usr, @email_usr, @codperf_usr, @contrasena_usr, @usercrea, @usermod, @fechcrea, @fechmod);
+END
+');
+GO
+
+IF OBJECT_ID(N'dbo.sp_usuarios_update', N'P') IS NULL EXEC(N'
+CREATE PROCEDURE dbo.sp_usuarios_update
+    @codusr_usr     INT,
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usercrea       INT,
+    @usermod        INT,
+    @fechcrea       DATETIME,
+    @fechmod        DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET nombre_usr = @nombre_usr,
+        email_usr = @email_usr,
+        codperf_usr = @codperf_usr,
+        contrasena_usr = @contrasena_usr,
+        usercrea = @usercrea,
+        usermod = @usermod,
+        fechcrea = @fechcrea,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr;
+END
+');
+GO
+
+IF OBJECT_ID(N'dbo.sp_usuarios_delete', N'P') IS NULL EXEC(N'
+CREATE PROCEDURE dbo.sp_usuarios_delete
+    @codusr_usr INT
+AS
+BEGIN
+    SET NOCOUNT ON;
+    DELETE FROM dbo.usuarios WHERE codusr_usr = @codusr_usr;
+END
+');
+GO
+
+IF OBJECT_ID(N'dbo.SP_VALIDATE_TOKEN', N'P') IS NULL EXEC(N'
+CREATE PROCEDURE dbo.SP_VALIDATE_TOKEN
+    @token VARCHAR(64)
+AS
+BEGIN
+    SET NOCOUNT ON;
+    SELECT TokenID, UserID, token, expiredDate
+    FROM dbo.usertoken
+    WHERE token = @token AND expired = 0 AND expiredDate > GETUTCDATE();
+END
+');
+GO
+
+IF OBJECT_ID(N'dbo.SP_GET_USER_TOKEN', N'P') IS NULL EXEC(N'
+CREATE PROCEDURE dbo.SP_GET_USER_TOKEN
+    @token VARCHAR(64)
+AS
+BEGIN
+    SET NOCOUNT ON;
+    SELECT t.TokenID, t.UserID, u.nombre_usr, u.email_usr, u.codperf_usr, t.expiredDate, t.expired
+    FROM dbo.usertoken t
+    INNER JOIN dbo.usuarios u ON u.codusr_usr = t.UserID
+    WHERE t.token = @token;
+END
+');
+GO
+
+IF OBJECT_ID(N'dbo.SP_LOGOUT', N'P') IS NULL EXEC(N'
+CREATE PROCEDURE dbo.SP_LOGOUT
+    @token VARCHAR(64)
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usertoken SET expired = 1, fechmod = GETUTCDATE() WHERE token = @token AND expired = 0;
+    SELECT @@ROWCOUNT AS revoked;
+END
+');
@@ -0,0 +1,36 @@
+-- sp_usuarios_update/delete report how many rows they touched, so a missing or concurrently deleted user is a 404
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_update
+    @codusr_usr     INT,
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usercrea       INT,
+    @usermod        INT,
+    @fechcrea       DATETIME,
+    @fechmod        DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET nombre_usr = @nombre_usr,
+        email_usr = @email_usr,
+        codperf_usr = @codperf_usr,
+        contrasena_usr = @contrasena_usr,
+        usercrea = @usercrea,
+        usermod = @usermod,
+        fechcrea = @fechcrea,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_delete
+    @codusr_usr INT
+AS
+BEGIN
+    SET NOCOUNT ON;
+    DELETE FROM dbo.usuarios WHERE codusr_usr = @codusr_usr;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
@@ -0,0 +1,17 @@
+-- Back to the 0001 procedure, which returns no result set
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_insert
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usercrea       INT,
+    @usermod        INT,
+    @fechcrea       DATETIME,
+    @fechmod        DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    INSERT INTO dbo.usuarios (nombre_usr, email_usr, codperf_usr, contrasena_usr, usercrea, usermod, fechcrea, fechmod)
+    VALUES (@nombre_usr, @email_usr, @codperf_usr, @contrasena_usr, @usercrea, @usermod, @fechcrea, @fechmod);
+END
+GO
@@ -0,0 +1,18 @@
+-- sp_usuarios_insert returns the generated codusr_usr instead of leaving callers to look the row up by name
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_insert
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usercrea       INT,
+    @usermod        INT,
+    @fechcrea       DATETIME,
+    @fechmod        DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    INSERT INTO dbo.usuarios (nombre_usr, email_usr, codperf_usr, contrasena_usr, usercrea, usermod, fechcrea, fechmod)
+    VALUES (@nombre_usr, @email_usr, @codperf_usr, @contrasena_usr, @usercrea, @usermod, @fechcrea, @fechmod);
+    SELECT CAST(SCOPE_IDENTITY() AS INT) AS codusr_usr;
+END
+GO
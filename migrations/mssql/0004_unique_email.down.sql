@@ -0,0 +1,2 @@
+IF INDEXPROPERTY(OBJECT_ID(N'dbo.usuarios'), N'UX_usuarios_email', 'IndexID') IS NOT NULL
+    DROP INDEX UX_usuarios_email ON dbo.usuarios;
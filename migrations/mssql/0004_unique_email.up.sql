@@ -0,0 +1,3 @@
+-- Only real addresses must be unique. Fails if the table already holds duplicate emails; clean those up first.
+IF INDEXPROPERTY(OBJECT_ID(N'dbo.usuarios'), N'UX_usuarios_email', 'IndexID') IS NULL
+    CREATE UNIQUE INDEX UX_usuarios_email ON dbo.usuarios (email_usr) WHERE email_usr IS NOT NULL AND email_usr <> N'';
@@ -0,0 +1,42 @@
+-- Soft-deleted users become visible again once deleted_at is dropped
+DROP PROCEDURE IF EXISTS dbo.sp_usuarios_purge;
+DROP PROCEDURE IF EXISTS dbo.sp_usuarios_restore;
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_update
+    @codusr_usr     INT,
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usercrea       INT,
+    @usermod        INT,
+    @fechcrea       DATETIME,
+    @fechmod        DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET nombre_usr = @nombre_usr,
+        email_usr = @email_usr,
+        codperf_usr = @codperf_usr,
+        contrasena_usr = @contrasena_usr,
+        usercrea = @usercrea,
+        usermod = @usermod,
+        fechcrea = @fechcrea,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_delete
+    @codusr_usr INT
+AS
+BEGIN
+    SET NOCOUNT ON;
+    DELETE FROM dbo.usuarios WHERE codusr_usr = @codusr_usr;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+IF COL_LENGTH(N'dbo.usuarios', N'deleted_at') IS NOT NULL
+    ALTER TABLE dbo.usuarios DROP COLUMN deleted_at;
+GO
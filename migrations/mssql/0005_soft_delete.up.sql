@@ -0,0 +1,77 @@
+-- Soft delete: sp_usuarios_delete stamps deleted_at instead of removing the row, deleted users can no longer be
+-- updated, and the row only goes away through sp_usuarios_purge, which takes the user's tokens with it.
+IF COL_LENGTH(N'dbo.usuarios', N'deleted_at') IS NULL
+    ALTER TABLE dbo.usuarios ADD deleted_at DATETIME NULL;
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_update
+    @codusr_usr     INT,
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usercrea       INT,
+    @usermod        INT,
+    @fechcrea       DATETIME,
+    @fechmod        DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET nombre_usr = @nombre_usr,
+        email_usr = @email_usr,
+        codperf_usr = @codperf_usr,
+        contrasena_usr = @contrasena_usr,
+        usercrea = @usercrea,
+        usermod = @usermod,
+        fechcrea = @fechcrea,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NULL;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_delete
+    @codusr_usr INT,
+    @usermod    INT,
+    @fechmod    DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET deleted_at = @fechmod,
+        usermod = @usermod,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NULL;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_restore
+    @codusr_usr INT,
+    @usermod    INT,
+    @fechmod    DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET deleted_at = NULL,
+        usermod = @usermod,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NOT NULL;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_purge
+    @codusr_usr INT
+AS
+BEGIN
+    SET NOCOUNT ON;
+    SET XACT_ABORT ON;
+    DECLARE @affected INT;
+    BEGIN TRANSACTION;
+    DELETE FROM dbo.refreshtoken WHERE UserID = @codusr_usr;
+    DELETE FROM dbo.usertoken WHERE UserID = @codusr_usr;
+    DELETE FROM dbo.usuarios WHERE codusr_usr = @codusr_usr;
+    SET @affected = @@ROWCOUNT;
+    COMMIT TRANSACTION;
+    SELECT @affected AS affected;
+END
+GO
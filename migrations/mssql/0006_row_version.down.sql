@@ -0,0 +1,63 @@
+-- Back to the 0005 procedures
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_update
+    @codusr_usr     INT,
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usercrea       INT,
+    @usermod        INT,
+    @fechcrea       DATETIME,
+    @fechmod        DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET nombre_usr = @nombre_usr,
+        email_usr = @email_usr,
+        codperf_usr = @codperf_usr,
+        contrasena_usr = @contrasena_usr,
+        usercrea = @usercrea,
+        usermod = @usermod,
+        fechcrea = @fechcrea,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NULL;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_delete
+    @codusr_usr INT,
+    @usermod    INT,
+    @fechmod    DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET deleted_at = @fechmod,
+        usermod = @usermod,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NULL;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_restore
+    @codusr_usr INT,
+    @usermod    INT,
+    @fechmod    DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET deleted_at = NULL,
+        usermod = @usermod,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NOT NULL;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+IF COL_LENGTH(N'dbo.usuarios', N'row_version') IS NOT NULL
+BEGIN
+    ALTER TABLE dbo.usuarios DROP CONSTRAINT DF_usuarios_row_version;
+    ALTER TABLE dbo.usuarios DROP COLUMN row_version;
+END
+GO
@@ -0,0 +1,66 @@
+-- row_version backs the ETag of a user: every write through the procedures bumps it, and sp_usuarios_update
+-- only writes when @row_version is NULL or still matches.
+IF COL_LENGTH(N'dbo.usuarios', N'row_version') IS NULL
+    ALTER TABLE dbo.usuarios ADD row_version INT NOT NULL CONSTRAINT DF_usuarios_row_version DEFAULT 1;
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_update
+    @codusr_usr     INT,
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usercrea       INT,
+    @usermod        INT,
+    @fechcrea       DATETIME,
+    @fechmod        DATETIME,
+    @row_version    INT = NULL
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET nombre_usr = @nombre_usr,
+        email_usr = @email_usr,
+        codperf_usr = @codperf_usr,
+        contrasena_usr = @contrasena_usr,
+        usercrea = @usercrea,
+        usermod = @usermod,
+        fechcrea = @fechcrea,
+        fechmod = @fechmod,
+        row_version = row_version + 1
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NULL
+      AND (@row_version IS NULL OR row_version = @row_version);
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_delete
+    @codusr_usr INT,
+    @usermod    INT,
+    @fechmod    DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET deleted_at = @fechmod,
+        row_version = row_version + 1,
+        usermod = @usermod,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NULL;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_restore
+    @codusr_usr INT,
+    @usermod    INT,
+    @fechmod    DATETIME
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET deleted_at = NULL,
+        row_version = row_version + 1,
+        usermod = @usermod,
+        fechmod = @fechmod
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NOT NULL;
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
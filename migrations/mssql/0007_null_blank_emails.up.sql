@@ -0,0 +1,2 @@
+-- A missing email is NULL on every backend; earlier versions stored it as '' on SQL Server
+UPDATE dbo.usuarios SET email_usr = NULL WHERE email_usr = N'';
@@ -0,0 +1,2 @@
+IF INDEXPROPERTY(OBJECT_ID(N'dbo.usuarios'), N'UX_usuarios_nombre_usr', 'IndexID') IS NOT NULL
+    DROP INDEX UX_usuarios_nombre_usr ON dbo.usuarios;
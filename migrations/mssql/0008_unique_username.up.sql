@@ -0,0 +1,5 @@
+-- 0001 only declares UQ_usuarios_nombre_usr when it creates the table, so an adopted usuarios may have no unique
+-- username at all. Fails if the table already holds duplicate usernames; clean those up first.
+IF INDEXPROPERTY(OBJECT_ID(N'dbo.usuarios'), N'UQ_usuarios_nombre_usr', 'IndexID') IS NULL
+    AND INDEXPROPERTY(OBJECT_ID(N'dbo.usuarios'), N'UX_usuarios_nombre_usr', 'IndexID') IS NULL
+    CREATE UNIQUE INDEX UX_usuarios_nombre_usr ON dbo.usuarios (nombre_usr);
@@ -0,0 +1,30 @@
+-- Back to the 0006 procedure
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_update
+    @codusr_usr     INT,
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usercrea       INT,
+    @usermod        INT,
+    @fechcrea       DATETIME,
+    @fechmod        DATETIME,
+    @row_version    INT = NULL
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET nombre_usr = @nombre_usr,
+        email_usr = @email_usr,
+        codperf_usr = @codperf_usr,
+        contrasena_usr = @contrasena_usr,
+        usercrea = @usercrea,
+        usermod = @usermod,
+        fechcrea = @fechcrea,
+        fechmod = @fechmod,
+        row_version = row_version + 1
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NULL
+      AND (@row_version IS NULL OR row_version = @row_version);
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
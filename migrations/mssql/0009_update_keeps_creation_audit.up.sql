@@ -0,0 +1,27 @@
+-- sp_usuarios_update no longer takes or writes the creation audit (who created the user and when), so callers
+-- no longer have to read it first and pass it back unchanged.
+CREATE OR ALTER PROCEDURE dbo.sp_usuarios_update
+    @codusr_usr     INT,
+    @nombre_usr     NVARCHAR(100),
+    @email_usr      NVARCHAR(150),
+    @codperf_usr    INT,
+    @contrasena_usr NVARCHAR(100),
+    @usermod        INT,
+    @fechmod        DATETIME,
+    @row_version    INT = NULL
+AS
+BEGIN
+    SET NOCOUNT ON;
+    UPDATE dbo.usuarios
+    SET nombre_usr = @nombre_usr,
+        email_usr = @email_usr,
+        codperf_usr = @codperf_usr,
+        contrasena_usr = @contrasena_usr,
+        usermod = @usermod,
+        fechmod = @fechmod,
+        row_version = row_version + 1
+    WHERE codusr_usr = @codusr_usr AND deleted_at IS NULL
+      AND (@row_version IS NULL OR row_version = @row_version);
+    SELECT @@ROWCOUNT AS affected;
+END
+GO
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS refreshtoken;
+DROP TABLE IF EXISTS usertoken;
+DROP TABLE IF EXISTS usuarios;
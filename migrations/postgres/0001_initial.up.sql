@@ -0,0 +1,47 @@
+-- IF NOT EXISTS so that databases created before migrations were tracked can be adopted
+CREATE TABLE IF NOT EXISTS usuarios (
+    codusr_usr     SERIAL PRIMARY KEY,
+    nombre_usr     VARCHAR(100) NOT NULL UNIQUE,
+    email_usr      VARCHAR(150) NULL,
+    codperf_usr    INTEGER NOT NULL,
+    contrasena_usr VARCHAR(100) NOT NULL,
+    usercrea       INTEGER NULL,
+    usermod        INTEGER NULL,
+    fechcrea       TIMESTAMP NULL,
+    fechmod        TIMESTAMP NULL
+);
+CREATE TABLE IF NOT EXISTS usertoken (
+    TokenID     SERIAL PRIMARY KEY,
+    UserID      INTEGER NOT NULL,
+    token       VARCHAR(64) NOT NULL,
+    FamilyID    VARCHAR(36) NULL,
+    DeviceName  VARCHAR(100) NULL,
+    UserAgent   VARCHAR(400) NULL,
+    IpAddress   VARCHAR(45) NULL,
+    createdDate TIMESTAMP NOT NULL,
+    LastSeen    TIMESTAMP NULL,
+    expiredDate TIMESTAMP NOT NULL,
+    expired     BOOLEAN NOT NULL DEFAULT FALSE,
+    usercrea    INTEGER NULL,
+    usermod     INTEGER NULL,
+    fechcrea    TIMESTAMP NULL,
+    fechmod     TIMESTAMP NULL
+);
+CREATE INDEX IF NOT EXISTS IX_usertoken_token ON usertoken (token);
+CREATE INDEX IF NOT EXISTS IX_usertoken_family ON usertoken (FamilyID);
+CREATE TABLE IF NOT EXISTS refreshtoken (
+    RefreshID   SERIAL PRIMARY KEY,
+    UserID      INTEGER NOT NULL,
+    FamilyID    VARCHAR(36) NOT NULL,
+    token       VARCHAR(64) NOT NULL,
+    createdDate TIMESTAMP NOT NULL,
+    expiredDate TIMESTAMP NOT NULL,
+    used        BOOLEAN NOT NULL DEFAULT FALSE,
+    revoked     BOOLEAN NOT NULL DEFAULT FALSE,
+    usercrea    INTEGER NULL,
+    usermod     INTEGER NULL,
+    fechcrea    TIMESTAMP NULL,
+    fechmod     TIMESTAMP NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS IX_refreshtoken_token ON refreshtoken (token);
+CREATE INDEX IF NOT EXISTS IX_refreshtoken_family ON refreshtoken (FamilyID);
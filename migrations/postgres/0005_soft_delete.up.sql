@@ -0,0 +1,2 @@
+-- Set when a user is soft-deleted; the row stays until it is purged
+ALTER TABLE usuarios ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP;
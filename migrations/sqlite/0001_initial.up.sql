@@ -0,0 +1,47 @@
+-- IF NOT EXISTS so that databases created before migrations were tracked can be adopted
+CREATE TABLE IF NOT EXISTS usuarios (
+    codusr_usr     INTEGER PRIMARY KEY AUTOINCREMENT,
+    nombre_usr     TEXT NOT NULL UNIQUE,
+    email_usr      TEXT NULL,
+    codperf_usr    INTEGER NOT NULL,
+    contrasena_usr TEXT NOT NULL,
+    usercrea       INTEGER NULL,
+    usermod        INTEGER NULL,
+    fechcrea       TEXT NULL,
+    fechmod        TEXT NULL
+);
+CREATE TABLE IF NOT EXISTS usertoken (
+    TokenID     INTEGER PRIMARY KEY AUTOINCREMENT,
+    UserID      INTEGER NOT NULL,
+    token       TEXT NOT NULL,
+    FamilyID    TEXT NULL,
+    DeviceName  TEXT NULL,
+    UserAgent   TEXT NULL,
+    IpAddress   TEXT NULL,
+    createdDate TEXT NOT NULL,
+    LastSeen    TEXT NULL,
+    expiredDate TEXT NOT NULL,
+    expired     INTEGER NOT NULL DEFAULT 0,
+    usercrea    INTEGER NULL,
+    usermod     INTEGER NULL,
+    fechcrea    TEXT NULL,
+    fechmod     TEXT NULL
+);
+CREATE INDEX IF NOT EXISTS IX_usertoken_token ON usertoken (token);
+CREATE INDEX IF NOT EXISTS IX_usertoken_family ON usertoken (FamilyID);
+CREATE TABLE IF NOT EXISTS refreshtoken (
+    RefreshID   INTEGER PRIMARY KEY AUTOINCREMENT,
+    UserID      INTEGER NOT NULL,
+    FamilyID    TEXT NOT NULL,
+    token       TEXT NOT NULL,
+    createdDate TEXT NOT NULL,
+    expiredDate TEXT NOT NULL,
+    used        INTEGER NOT NULL DEFAULT 0,
+    revoked     INTEGER NOT NULL DEFAULT 0,
+    usercrea    INTEGER NULL,
+    usermod     INTEGER NULL,
+    fechcrea    TEXT NULL,
+    fechmod     TEXT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS IX_refreshtoken_token ON refreshtoken (token);
+CREATE INDEX IF NOT EXISTS IX_refreshtoken_family ON refreshtoken (FamilyID);
@@ -0,0 +1,2 @@
+-- Only real addresses must be unique. Fails if the table already holds duplicate emails; clean those up first.
+CREATE UNIQUE INDEX IF NOT EXISTS UX_usuarios_email ON usuarios (email_usr) WHERE email_usr IS NOT NULL AND email_usr <> '';
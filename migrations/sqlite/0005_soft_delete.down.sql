@@ -0,0 +1,2 @@
+-- Soft-deleted users become visible again
+ALTER TABLE usuarios DROP COLUMN deleted_at;
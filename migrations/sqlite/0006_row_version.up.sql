@@ -0,0 +1,2 @@
+-- Bumped by every write to the user; backs its ETag
+ALTER TABLE usuarios ADD COLUMN row_version INTEGER NOT NULL DEFAULT 1;
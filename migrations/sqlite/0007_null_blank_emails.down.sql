@@ -0,0 +1 @@
+-- Nothing to undo: which NULL emails used to be '' is not recorded, and both mean "no email"
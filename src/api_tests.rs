@@ -0,0 +1,659 @@
+// End-to-end tests over the real routes, backed by the in-memory repositories or an in-memory SQLite
+// database, so no database server is needed. Setting TEST_POSTGRES_URL also runs them against that Postgres
+// database, which each scenario wipes: point it at a throwaway one.
+use crate::auth::Denylist;
+use crate::config::{Settings, StorageBackend, TokenMode};
+use crate::models::CreateUser;
+use crate::db::Database;
+use crate::migrations;
+use crate::repository::{self, TokenStore, UserQuery, UserRepository};
+use crate::routes;
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, Error};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const ADMIN_PASSWORD: &str = "root-password";
+const TEST_POSTGRES_URL: &str = "TEST_POSTGRES_URL";
+
+fn settings(token_mode: TokenMode) -> Settings {
+    Settings { token_mode, ..Settings::for_tests() }
+}
+
+async fn database(backend: StorageBackend, cfg: &Settings) -> Database {
+    let database = match backend {
+        StorageBackend::Memory => Database::Memory,
+        StorageBackend::Sqlite => Database::Sqlite(repository::sqlite::connect(cfg, "sqlite::memory:").await.unwrap()),
+        StorageBackend::Postgres => {
+            let url = std::env::var(TEST_POSTGRES_URL).unwrap();
+            let database = Database::Postgres(repository::postgres::connect(cfg, &url).await.unwrap());
+            while migrations::down(&database).await.unwrap().is_some() {}
+            database
+        }
+        other => panic!("no test database for {:?}", other),
+    };
+    migrations::up(&database).await.unwrap();
+    database
+}
+
+async fn stores(backend: StorageBackend, cfg: &Settings) -> (Arc<dyn UserRepository>, Arc<dyn TokenStore>) {
+    database(backend, cfg).await.repositories()
+}
+
+// The app as main builds it, with an admin account ("root") already seeded
+async fn init_app(backend: StorageBackend, cfg: Settings) -> (impl Service<Request, Response = ServiceResponse, Error = Error>, i32) {
+    let database = database(backend, &cfg).await;
+    init_app_on(database, cfg).await
+}
+
+async fn init_app_on(database: Database, cfg: Settings) -> (impl Service<Request, Response = ServiceResponse, Error = Error>, i32) {
+    let (users, tokens) = database.repositories();
+    let admin = users
+        .create(CreateUser { username: "root".into(), email: Some("root@example.com".into()), password: ADMIN_PASSWORD.into() }, cfg.admin_profile_id, 0)
+        .await
+        .unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::from(users))
+            .app_data(web::Data::from(tokens))
+            .app_data(web::Data::new(cfg))
+            .app_data(web::Data::new(Denylist::default()))
+            .configure(routes::configure),
+    )
+    .await;
+    (app, admin.id)
+}
+
+// Middleware rejections come back as errors rather than responses; both are reduced to status + JSON body
+async fn send(app: &impl Service<Request, Response = ServiceResponse, Error = Error>, req: test::TestRequest) -> (StatusCode, Value) {
+    let res = match test::try_call_service(app, req.to_request()).await {
+        Ok(res) => res.into_parts().1,
+        Err(e) => e.error_response(),
+    };
+    let status = res.status();
+    let body = actix_web::body::to_bytes(res.into_body()).await.unwrap_or_default();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+fn bearer(token: &str) -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", token))
+}
+
+async fn login(app: &impl Service<Request, Response = ServiceResponse, Error = Error>, username: &str, password: &str) -> Value {
+    let (status, body) = send(app, test::TestRequest::post().uri("/login").set_json(json!({ "username": username, "password": password }))).await;
+    assert_eq!(status, StatusCode::OK, "login as {}", username);
+    body
+}
+
+// The bcrypt hash never leaves the server: neither the field nor anything with its `$2` prefix
+fn assert_no_hash(body: &Value) {
+    let text = body.to_string();
+    assert!(!text.contains("password_hash") && !text.contains("$2"), "password hash in response: {}", text);
+}
+
+async fn user_lifecycle(backend: StorageBackend, token_mode: TokenMode) {
+    let (app, admin_id) = init_app(backend, settings(token_mode)).await;
+
+    let (status, created) = send(&app, test::TestRequest::post().uri("/users").set_json(json!({ "username": "ana", "email": "ana@example.com", "password": "ana-passw0rd" }))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["username"], "ana");
+    assert_no_hash(&created);
+    let id = created["id"].as_i64().unwrap();
+
+    let (status, body) = send(&app, test::TestRequest::post().uri("/users").set_json(json!({ "username": "x", "email": "nope", "password": "short" }))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["code"], "validation_failed");
+    let fields: Vec<&str> = body["errors"].as_array().unwrap().iter().map(|e| e["field"].as_str().unwrap()).collect();
+    assert_eq!(fields, ["email", "password", "password", "username"]);
+
+    let (status, body) = send(&app, test::TestRequest::post().uri("/users").set_json(json!({ "username": "ana", "password": "other-pass1" }))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], "username_taken");
+    let (status, body) = send(&app, test::TestRequest::post().uri("/users").set_json(json!({ "username": "ana2", "email": "root@example.com", "password": "other-pass1" }))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], "email_taken");
+
+    let (status, body) = send(&app, test::TestRequest::get().uri("/users/availability?username=ana&email=free@example.com")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "username": false, "email": true }));
+    let (status, body) = send(&app, test::TestRequest::get().uri("/users/availability?email=root@example.com")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "email": false }));
+    let (status, _) = send(&app, test::TestRequest::get().uri("/users/availability")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = send(&app, test::TestRequest::post().uri("/login").set_json(json!({ "username": "ana", "password": "wrong" }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "invalid_credentials");
+    let (status, body) = send(&app, test::TestRequest::post().uri("/login").set_json(json!({ "username": "ana" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "bad_request");
+
+    let ana = login(&app, "ana", "ana-passw0rd").await["token"].as_str().unwrap().to_string();
+
+    let (status, body) = send(&app, test::TestRequest::get().uri(&format!("/users/{}", id))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "unauthorized");
+
+    let (status, body) = send(&app, test::TestRequest::get().uri(&format!("/users/{}", id)).insert_header(bearer(&ana))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["email"], "ana@example.com");
+    assert_no_hash(&body);
+
+    let (status, body) = send(&app, test::TestRequest::patch().uri(&format!("/users/{}", id)).insert_header(bearer(&ana)).set_json(json!({ "email": "ana@new.example.com" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["email"], "ana@new.example.com");
+    assert_no_hash(&body);
+    let (status, body) = send(&app, test::TestRequest::patch().uri(&format!("/users/{}", id)).insert_header(bearer(&ana)).set_json(json!({ "username": "root" }))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], "username_taken");
+
+    // A regular user can neither promote itself nor read or touch other accounts or admin routes
+    let (status, _) = send(&app, test::TestRequest::patch().uri(&format!("/users/{}", id)).insert_header(bearer(&ana)).set_json(json!({ "profile": 2 }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = send(&app, test::TestRequest::delete().uri(&format!("/users/{}", admin_id)).insert_header(bearer(&ana))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "forbidden");
+    let (status, body) = send(&app, test::TestRequest::get().uri(&format!("/users/{}", admin_id)).insert_header(bearer(&ana))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(body.get("email").is_none());
+    let (status, _) = send(&app, test::TestRequest::get().uri("/load_concurrent").insert_header(bearer(&ana))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Login accepts the email too
+    let root = login(&app, "root@example.com", ADMIN_PASSWORD).await["token"].as_str().unwrap().to_string();
+    let (status, body) = send(&app, test::TestRequest::get().uri("/users").insert_header(bearer(&root))).await;
+    assert_eq!((status, body["total"].as_i64()), (StatusCode::OK, Some(2)));
+    assert_no_hash(&body);
+    let (status, body) = send(&app, test::TestRequest::get().uri("/load_concurrent").insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_no_hash(&body);
+    let mut names: Vec<&str> = body.as_array().unwrap().iter().map(|u| u["username"].as_str().unwrap()).collect();
+    names.sort();
+    assert_eq!(names, ["ana", "root"]);
+
+    let (status, _) = send(&app, test::TestRequest::post().uri("/logout").insert_header(bearer(&ana))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = send(&app, test::TestRequest::get().uri(&format!("/users/{}", id)).insert_header(bearer(&ana))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&app, test::TestRequest::post().uri("/logout").insert_header(bearer(&ana))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (status, _) = send(&app, test::TestRequest::delete().uri(&format!("/users/{}", id)).insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, body) = send(&app, test::TestRequest::get().uri(&format!("/users/{}", id)).insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["status"], 404);
+    let (status, _) = send(&app, test::TestRequest::delete().uri(&format!("/users/{}", id)).insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send(&app, test::TestRequest::patch().uri(&format!("/users/{}", id)).insert_header(bearer(&root)).set_json(json!({ "email": "gone@example.com" }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn user_lifecycle_with_opaque_tokens() {
+    user_lifecycle(StorageBackend::Memory, TokenMode::Opaque).await;
+}
+
+#[actix_web::test]
+async fn user_lifecycle_with_jwt() {
+    user_lifecycle(StorageBackend::Memory, TokenMode::Jwt).await;
+}
+
+#[actix_web::test]
+async fn user_lifecycle_on_sqlite() {
+    user_lifecycle(StorageBackend::Sqlite, TokenMode::Opaque).await;
+    user_lifecycle(StorageBackend::Sqlite, TokenMode::Jwt).await;
+}
+
+// POST /users is public, but a signed-in caller is recorded as the creator
+#[actix_web::test]
+async fn signups_record_the_signed_in_creator() {
+    for token_mode in [TokenMode::Opaque, TokenMode::Jwt] {
+        let cfg = settings(token_mode);
+        let pool = repository::sqlite::connect(&cfg, "sqlite::memory:").await.unwrap();
+        let database = Database::Sqlite(pool.clone());
+        migrations::up(&database).await.unwrap();
+        let (app, admin_id) = init_app_on(database, cfg).await;
+        let root = login(&app, "root", ADMIN_PASSWORD).await["token"].as_str().unwrap().to_string();
+
+        let signup = |name: &str| test::TestRequest::post().uri("/users").set_json(json!({ "username": name, "password": "passw0rd-123" }));
+        let (_, by_admin) = send(&app, signup("bea").insert_header(bearer(&root))).await;
+        let (_, anonymous) = send(&app, signup("carla")).await;
+        // A stale token does not block the signup, it just is not taken as the creator
+        let (status, stale) = send(&app, signup("dora").insert_header(bearer("not-a-token"))).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        for (user, creator) in [(by_admin, admin_id), (anonymous, 0), (stale, 0)] {
+            let (usercrea,) = sqlx::query_as::<_, (i32,)>("SELECT usercrea FROM usuarios WHERE codusr_usr = ?1").bind(user["id"].as_i64().unwrap()).fetch_one(&pool).await.unwrap();
+            assert_eq!(usercrea, creator);
+        }
+    }
+}
+
+async fn refresh_rotation(backend: StorageBackend) {
+    let (app, _) = init_app(backend, settings(TokenMode::Opaque)).await;
+    let first = login(&app, "root", ADMIN_PASSWORD).await;
+
+    let (status, second) = send(&app, test::TestRequest::post().uri("/token/refresh").set_json(json!({ "refresh_token": first["refresh_token"] }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(second["refresh_token"], first["refresh_token"]);
+    // The renewed access token replaces the old one on the same session
+    let (status, _) = send(&app, test::TestRequest::get().uri("/users").insert_header(bearer(second["token"].as_str().unwrap()))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, test::TestRequest::get().uri("/users").insert_header(bearer(first["token"].as_str().unwrap()))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Replaying the rotated token burns the whole family, including the token issued by the rotation
+    let (status, _) = send(&app, test::TestRequest::post().uri("/token/refresh").set_json(json!({ "refresh_token": first["refresh_token"] }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&app, test::TestRequest::post().uri("/token/refresh").set_json(json!({ "refresh_token": second["refresh_token"] }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn refresh_token_rotates_and_reuse_revokes_the_session() {
+    refresh_rotation(StorageBackend::Memory).await;
+    refresh_rotation(StorageBackend::Sqlite).await;
+}
+
+// A JWT stays verifiable until it expires, so logging out has to deny the ones minted before the last refresh too
+async fn jwt_logout_after_refresh(backend: StorageBackend) {
+    let (app, _) = init_app(backend, settings(TokenMode::Jwt)).await;
+    let first = login(&app, "root", ADMIN_PASSWORD).await;
+    let (status, second) = send(&app, test::TestRequest::post().uri("/token/refresh").set_json(json!({ "refresh_token": first["refresh_token"] }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (old, new) = (first["token"].as_str().unwrap(), second["token"].as_str().unwrap());
+    assert_ne!(old, new);
+
+    let (status, _) = send(&app, test::TestRequest::post().uri("/logout").insert_header(bearer(new))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    for token in [old, new] {
+        let (status, _) = send(&app, test::TestRequest::get().uri("/users").insert_header(bearer(token))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[actix_web::test]
+async fn jwt_logout_revokes_tokens_minted_before_a_refresh() {
+    jwt_logout_after_refresh(StorageBackend::Memory).await;
+    jwt_logout_after_refresh(StorageBackend::Sqlite).await;
+}
+
+async fn session_management(backend: StorageBackend, token_mode: TokenMode) {
+    let (app, _) = init_app(backend, settings(token_mode)).await;
+    let laptop = login(&app, "root", ADMIN_PASSWORD).await["token"].as_str().unwrap().to_string();
+    let phone = login(&app, "root", ADMIN_PASSWORD).await["token"].as_str().unwrap().to_string();
+
+    let (status, sessions) = send(&app, test::TestRequest::get().uri("/me/sessions").insert_header(bearer(&laptop))).await;
+    assert_eq!(status, StatusCode::OK);
+    let sessions = sessions.as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    let other = sessions.iter().find(|s| s["current"] == false).unwrap()["id"].as_i64().unwrap();
+
+    let (status, _) = send(&app, test::TestRequest::delete().uri(&format!("/me/sessions/{}", other)).insert_header(bearer(&laptop))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = send(&app, test::TestRequest::get().uri("/me/sessions").insert_header(bearer(&phone))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&app, test::TestRequest::get().uri("/me/sessions").insert_header(bearer(&laptop))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&app, test::TestRequest::post().uri("/logout/all").insert_header(bearer(&laptop))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = send(&app, test::TestRequest::get().uri("/me/sessions").insert_header(bearer(&laptop))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // A new login right after logout-all is not caught by it, even within the same second
+    let again = login(&app, "root", ADMIN_PASSWORD).await["token"].as_str().unwrap().to_string();
+    let (status, _) = send(&app, test::TestRequest::get().uri("/me/sessions").insert_header(bearer(&again))).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn sessions_are_listed_and_revoked_individually() {
+    session_management(StorageBackend::Memory, TokenMode::Opaque).await;
+    session_management(StorageBackend::Memory, TokenMode::Jwt).await;
+    session_management(StorageBackend::Sqlite, TokenMode::Opaque).await;
+}
+
+async fn user_listing(backend: StorageBackend) {
+    let cfg = Settings { max_page_size: 2, ..settings(TokenMode::Opaque) };
+    let (app, _) = init_app(backend, cfg).await;
+    for name in ["bea", "carla", "dora_x", "dorax"] {
+        let (status, _) = send(&app, test::TestRequest::post().uri("/users").set_json(json!({ "username": name, "email": format!("{}@example.com", name), "password": "passw0rd-123" }))).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let root = login(&app, "root", ADMIN_PASSWORD).await["token"].as_str().unwrap().to_string();
+    let list = |uri: &str| test::TestRequest::get().uri(uri).insert_header(bearer(&root));
+    let names = |body: &Value| body["items"].as_array().unwrap().iter().map(|u| u["username"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+
+    // page_size is capped at MAX_PAGE_SIZE (2 here)
+    let (status, body) = send(&app, list("/users?page_size=50&sort=-username")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!((body["total"].as_i64(), body["total_pages"].as_i64(), body["page_size"].as_i64()), (Some(5), Some(3), Some(2)));
+    assert_eq!(names(&body), ["root", "dorax"]);
+    assert!(body["links"]["prev"].is_null());
+    let next = body["links"]["next"].as_str().unwrap().to_string();
+    assert_eq!(next, "/users?page=2&page_size=2&sort=-username");
+
+    let (_, body) = send(&app, list(&next)).await;
+    assert_eq!(names(&body), ["dora_x", "carla"]);
+    assert_eq!(body["links"]["prev"], "/users?page=1&page_size=2&sort=-username");
+
+    // '_' is matched literally, and filters combine
+    let (_, body) = send(&app, list("/users?username=A_")).await;
+    assert_eq!(names(&body), ["dora_x"]);
+    let (_, body) = send(&app, list("/users?email=EXAMPLE.com&profile=2")).await;
+    assert_eq!(names(&body), ["root"]);
+    assert!(body["links"]["next"].is_null());
+
+    // /load_concurrent reads one page too, never the whole table
+    let (status, body) = send(&app, list("/load_concurrent?page_size=50&sort=-username")).await;
+    assert_eq!(status, StatusCode::OK);
+    let mut loaded: Vec<&str> = body.as_array().unwrap().iter().map(|u| u["username"].as_str().unwrap()).collect();
+    loaded.sort();
+    assert_eq!(loaded, ["dorax", "root"]);
+
+    let (status, body) = send(&app, list("/users?sort=password")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "bad_request");
+}
+
+#[actix_web::test]
+async fn users_are_paged_filtered_and_sorted() {
+    user_listing(StorageBackend::Memory).await;
+    user_listing(StorageBackend::Sqlite).await;
+}
+
+async fn soft_delete(backend: StorageBackend, token_mode: TokenMode) {
+    let (app, _) = init_app(backend, settings(token_mode)).await;
+    let create = || test::TestRequest::post().uri("/users").set_json(json!({ "username": "eva", "email": "eva@example.com", "password": "eva-passw0rd" }));
+    let (_, eva) = send(&app, create()).await;
+    let id = eva["id"].as_i64().unwrap();
+    let eva_token = login(&app, "eva", "eva-passw0rd").await["token"].as_str().unwrap().to_string();
+    let root = login(&app, "root", ADMIN_PASSWORD).await["token"].as_str().unwrap().to_string();
+    let user_uri = format!("/users/{}", id);
+
+    // Only admins purge, even their own account
+    let (status, _) = send(&app, test::TestRequest::delete().uri(&format!("{}?purge=true", user_uri)).insert_header(bearer(&eva_token))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Soft delete hides the user and ends its sessions, but keeps the name taken
+    let (status, _) = send(&app, test::TestRequest::delete().uri(&user_uri).insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = send(&app, test::TestRequest::get().uri(&user_uri).insert_header(bearer(&eva_token))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&app, test::TestRequest::get().uri(&user_uri).insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, body) = send(&app, test::TestRequest::get().uri("/users").insert_header(bearer(&root))).await;
+    assert_eq!((status, body["total"].as_i64()), (StatusCode::OK, Some(1)));
+    let (status, _) = send(&app, test::TestRequest::post().uri("/login").set_json(json!({ "username": "eva", "password": "eva-passw0rd" }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&app, create()).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = send(&app, test::TestRequest::delete().uri(&user_uri).insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, body) = send(&app, test::TestRequest::post().uri(&format!("{}/restore", user_uri)).insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["username"], "eva");
+    // Logging in right after the restore works even in JWT mode, where the deletion cut off every earlier token
+    let eva_token = login(&app, "eva", "eva-passw0rd").await["token"].as_str().unwrap().to_string();
+    let (status, _) = send(&app, test::TestRequest::post().uri(&format!("{}/restore", user_uri)).insert_header(bearer(&eva_token))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = send(&app, test::TestRequest::post().uri(&format!("{}/restore", user_uri)).insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Purge frees the name for good
+    let (status, _) = send(&app, test::TestRequest::delete().uri(&format!("{}?purge=true", user_uri)).insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = send(&app, test::TestRequest::get().uri("/me/sessions").insert_header(bearer(&eva_token))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&app, test::TestRequest::post().uri(&format!("{}/restore", user_uri)).insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send(&app, create()).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn deleted_users_are_hidden_until_restored_or_purged() {
+    soft_delete(StorageBackend::Memory, TokenMode::Opaque).await;
+    soft_delete(StorageBackend::Memory, TokenMode::Jwt).await;
+    soft_delete(StorageBackend::Sqlite, TokenMode::Opaque).await;
+}
+
+async fn etag_of(app: &impl Service<Request, Response = ServiceResponse, Error = Error>, req: test::TestRequest) -> (StatusCode, String) {
+    let res = test::call_service(app, req.to_request()).await;
+    let etag = res.headers().get("etag").map(|v| v.to_str().unwrap().to_string()).unwrap_or_default();
+    (res.status(), etag)
+}
+
+async fn optimistic_concurrency(backend: StorageBackend) {
+    let (app, _) = init_app(backend, settings(TokenMode::Opaque)).await;
+    let (_, leo) = send(&app, test::TestRequest::post().uri("/users").set_json(json!({ "username": "leo", "email": "leo@example.com", "password": "leo-passw0rd" }))).await;
+    let user_uri = format!("/users/{}", leo["id"]);
+    let token = login(&app, "leo", "leo-passw0rd").await["token"].as_str().unwrap().to_string();
+    let get = || test::TestRequest::get().uri(&user_uri).insert_header(bearer(&token));
+    let patch = |email: &str| test::TestRequest::patch().uri(&user_uri).insert_header(bearer(&token)).set_json(json!({ "email": email }));
+
+    let (status, first) = etag_of(&app, get()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!first.is_empty());
+    let (status, etag) = etag_of(&app, get().insert_header(("If-None-Match", first.clone()))).await;
+    assert_eq!((status, etag.as_str()), (StatusCode::NOT_MODIFIED, first.as_str()));
+
+    // Two clients read the same version; the second write loses
+    let (status, second) = etag_of(&app, patch("leo@one.example").insert_header(("If-Match", first.clone()))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(second, first);
+    let (status, body) = send(&app, patch("leo@two.example").insert_header(("If-Match", first.clone()))).await;
+    assert_eq!((status, body["code"].as_str()), (StatusCode::PRECONDITION_FAILED, Some("precondition_failed")));
+    let (_, body) = send(&app, get()).await;
+    assert_eq!(body["email"], "leo@one.example");
+
+    // A stale If-None-Match gets the full body and the new tag; no If-Match keeps last-write-wins
+    let (status, etag) = etag_of(&app, get().insert_header(("If-None-Match", first.clone()))).await;
+    assert_eq!((status, etag.as_str()), (StatusCode::OK, second.as_str()));
+    let (status, _) = send(&app, patch("leo@three.example").insert_header(("If-Match", "*"))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, patch("leo@four.example")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, patch("leo@five.example").insert_header(("If-Match", second))).await;
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    let (status, _) = send(&app, patch("leo@five.example").insert_header(("If-Match", "not-a-tag"))).await;
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+}
+
+#[actix_web::test]
+async fn stale_writes_are_rejected_with_412() {
+    optimistic_concurrency(StorageBackend::Memory).await;
+    optimistic_concurrency(StorageBackend::Sqlite).await;
+}
+
+async fn merge_patch_and_replace(backend: StorageBackend) {
+    let (app, _) = init_app(backend, settings(TokenMode::Opaque)).await;
+    let (_, mia) = send(&app, test::TestRequest::post().uri("/users").set_json(json!({ "username": "mia", "email": "mia@example.com", "password": "mia-passw0rd" }))).await;
+    let user_uri = format!("/users/{}", mia["id"]);
+    let token = login(&app, "mia", "mia-passw0rd").await["token"].as_str().unwrap().to_string();
+    let merge_patch = |patch: Value| {
+        test::TestRequest::patch()
+            .uri(&user_uri)
+            .insert_header(bearer(&token))
+            .insert_header(("Content-Type", "application/merge-patch+json"))
+            .set_payload(patch.to_string())
+    };
+    let put = |body: Value| test::TestRequest::put().uri(&user_uri).insert_header(bearer(&token)).set_json(body);
+
+    // Absent fields stay, null clears the email, null on a required field is rejected
+    let (status, body) = send(&app, merge_patch(json!({ "username": "mia2" }))).await;
+    assert_eq!((status, body["email"].as_str()), (StatusCode::OK, Some("mia@example.com")));
+    assert_no_hash(&body);
+    let (status, body) = send(&app, merge_patch(json!({ "email": null }))).await;
+    assert_eq!((status, &body["email"], body["username"].as_str()), (StatusCode::OK, &Value::Null, Some("mia2")));
+    let (_, body) = send(&app, test::TestRequest::get().uri(&user_uri).insert_header(bearer(&token))).await;
+    assert_eq!(body["email"], Value::Null);
+    let (status, _) = send(&app, merge_patch(json!({ "username": null }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // PUT replaces everything: a missing email is cleared, username and profile are required
+    let (status, body) = send(&app, put(json!({ "username": "mia", "email": "mia@new.example", "profile": 1 }))).await;
+    assert_eq!((status, body["email"].as_str()), (StatusCode::OK, Some("mia@new.example")));
+    assert_no_hash(&body);
+    let (status, body) = send(&app, put(json!({ "username": "mia", "profile": 1 }))).await;
+    assert_eq!((status, &body["email"]), (StatusCode::OK, &Value::Null));
+    let (status, _) = send(&app, put(json!({ "email": "mia@example.com", "profile": 1 }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(&app, put(json!({ "username": "mia", "profile": 2 }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // The password is write-only, so a PUT without it keeps the current one
+    login(&app, "mia", "mia-passw0rd").await;
+}
+
+#[actix_web::test]
+async fn patch_merges_and_put_replaces() {
+    merge_patch_and_replace(StorageBackend::Memory).await;
+    merge_patch_and_replace(StorageBackend::Sqlite).await;
+}
+
+async fn bulk_import(backend: StorageBackend) {
+    let (app, _) = init_app(backend, settings(TokenMode::Opaque)).await;
+    let root = login(&app, "root", ADMIN_PASSWORD).await["token"].as_str().unwrap().to_string();
+    let csv = |body: &str| {
+        test::TestRequest::post()
+            .uri("/users/import")
+            .insert_header(bearer(&root))
+            .insert_header(("Content-Type", "text/csv"))
+            .set_payload(body.to_string())
+    };
+    let total = || async { send(&app, test::TestRequest::get().uri("/users").insert_header(bearer(&root))).await.1["total"].as_i64() };
+
+    // One bad row rejects the whole file, and every row is reported
+    let (status, body) = send(&app, csv("username,email,password,profile\nuna,una@example.com,una-passw0rd,\ndos,not-an-email,dos-passw0rd,\nuna,,una-passw0rd,\nroot,,root-passw0rd,\n")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!((body["total"].as_i64(), body["invalid"].as_i64(), body["created"].as_i64()), (Some(4), Some(3), Some(0)));
+    let rows = body["rows"].as_array().unwrap();
+    assert_eq!(rows[0]["status"], "valid");
+    assert_eq!((rows[1]["row"].as_i64(), rows[1]["errors"][0]["field"].as_str()), (Some(2), Some("email")));
+    assert_eq!(rows[2]["errors"][0]["code"], "duplicate_in_import");
+    assert_eq!(rows[3]["errors"][0]["code"], "username_taken");
+    assert_eq!(total().await, Some(1));
+
+    // A dry run only validates
+    let good = "username,email,password,profile\nuna,una@example.com,una-passw0rd,\ndos,,dos-passw0rd,2\n";
+    let (status, body) = send(&app, csv(good).uri("/users/import?dry_run=true")).await;
+    assert_eq!((status, body["dry_run"].as_bool(), body["rows"][1]["status"].as_str()), (StatusCode::OK, Some(true), Some("valid")));
+    assert_eq!(total().await, Some(1));
+
+    let (status, body) = send(&app, csv(good)).await;
+    assert_eq!((status, body["created"].as_i64()), (StatusCode::CREATED, Some(2)));
+    let dos_id = body["rows"][1]["id"].as_i64().unwrap();
+    let (_, dos) = send(&app, test::TestRequest::get().uri(&format!("/users/{}", dos_id)).insert_header(bearer(&root))).await;
+    assert_eq!((&dos["email"], dos["profile"].as_i64()), (&Value::Null, Some(2)));
+    login(&app, "una", "una-passw0rd").await;
+
+    // JSON arrays take the same rows; only admins import
+    let json_rows = json!([{ "username": "tres", "password": "tres-passw0rd" }]);
+    let (status, body) = send(&app, test::TestRequest::post().uri("/users/import").insert_header(bearer(&root)).set_json(json_rows.clone())).await;
+    assert_eq!((status, body["rows"][0]["status"].as_str()), (StatusCode::CREATED, Some("created")));
+    let una = login(&app, "una", "una-passw0rd").await["token"].as_str().unwrap().to_string();
+    let (status, _) = send(&app, test::TestRequest::post().uri("/users/import").insert_header(bearer(&una)).set_json(json_rows)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = send(&app, test::TestRequest::post().uri("/users/import").insert_header(bearer(&root)).insert_header(("Content-Type", "text/plain")).set_payload("tres")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(total().await, Some(4));
+}
+
+#[actix_web::test]
+async fn users_are_imported_all_or_nothing() {
+    bulk_import(StorageBackend::Memory).await;
+    bulk_import(StorageBackend::Sqlite).await;
+}
+
+async fn create_many_rollback(backend: StorageBackend) {
+    let cfg = settings(TokenMode::Opaque);
+    let (users, _) = stores(backend, &cfg).await;
+    let new_user = |username: &str| (CreateUser { username: username.into(), email: None, password: "passw0rd-123".into() }, 1);
+    users.create(new_user("taken").0, 1, 0).await.unwrap();
+    let err = users.create_many(vec![new_user("first"), new_user("taken")], 0).await.unwrap_err();
+    assert!(err.is::<repository::Conflict>());
+    let everyone = UserQuery { username: None, email: None, profile: None, sort: Vec::new(), offset: 0, limit: 10 };
+    let names: Vec<String> = users.search(&everyone).await.unwrap().users.into_iter().map(|u| u.username).collect();
+    assert_eq!(names, ["taken"]);
+    let created = users.create_many(vec![new_user("first"), new_user("second")], 0).await.unwrap();
+    assert_eq!(created.iter().map(|u| u.username.as_str()).collect::<Vec<_>>(), ["first", "second"]);
+}
+
+#[actix_web::test]
+async fn create_many_rolls_back_on_a_conflict() {
+    create_many_rollback(StorageBackend::Memory).await;
+    create_many_rollback(StorageBackend::Sqlite).await;
+}
+
+async fn streaming_export(backend: StorageBackend) {
+    let (app, _) = init_app(backend, settings(TokenMode::Opaque)).await;
+    let root = login(&app, "root", ADMIN_PASSWORD).await["token"].as_str().unwrap().to_string();
+    let rows: Vec<Value> = (1..=30).map(|i| json!({ "username": format!("user{:03}", i), "email": format!("user{:03}@{}.example", i, if i % 2 == 0 { "even" } else { "odd" }), "password": "passw0rd-123", "profile": 1 + i % 3 })).collect();
+    let (status, body) = send(&app, test::TestRequest::post().uri("/users/import").insert_header(bearer(&root)).set_json(rows)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let deleted = body["rows"][1]["id"].as_i64().unwrap();
+    send(&app, test::TestRequest::delete().uri(&format!("/users/{}", deleted)).insert_header(bearer(&root))).await;
+    let export = |uri: &str| test::TestRequest::get().uri(uri).insert_header(bearer(&root)).to_request();
+
+    let res = test::call_service(&app, export("/users/export?format=csv")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get("content-type").unwrap(), "text/csv; charset=utf-8");
+    let csv = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 1 + 30);
+    assert_eq!(lines[0], "id,username,email,profile");
+    assert_eq!(lines[1], "1,root,root@example.com,2");
+    assert!(lines[2].ends_with(",user001,user001@odd.example,2"));
+    assert!(!csv.contains("user002,"));
+
+    // Same filters and sort as GET /users
+    let res = test::call_service(&app, export("/users/export?format=ndjson&email=even&profile=1&sort=-username")).await;
+    assert_eq!(res.headers().get("content-type").unwrap(), "application/x-ndjson");
+    let body = test::read_body(res).await;
+    let users: Vec<Value> = body.split(|b| *b == b'\n').filter(|l| !l.is_empty()).map(|l| serde_json::from_slice(l).unwrap()).collect();
+    let (_, page) = send(&app, test::TestRequest::get().uri("/users?email=even&profile=1&sort=-username&page_size=100").insert_header(bearer(&root))).await;
+    assert_eq!(users.len(), 5);
+    assert_eq!(&users, page["items"].as_array().unwrap());
+    assert!(users[0].get("password_hash").is_none());
+
+    let (status, _) = send(&app, test::TestRequest::get().uri("/users/export?format=xml").insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(&app, test::TestRequest::get().uri("/users/export?sort=password").insert_header(bearer(&root))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let user = login(&app, "user003", "passw0rd-123").await["token"].as_str().unwrap().to_string();
+    let (status, _) = send(&app, test::TestRequest::get().uri("/users/export").insert_header(bearer(&user))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn users_export_streams_csv_and_ndjson() {
+    streaming_export(StorageBackend::Memory).await;
+    streaming_export(StorageBackend::Sqlite).await;
+}
+
+// Every scenario above against Postgres, one after another since they share the database. Skipped unless
+// TEST_POSTGRES_URL is set.
+#[actix_web::test]
+async fn scenarios_on_postgres() {
+    if std::env::var(TEST_POSTGRES_URL).is_err() {
+        return;
+    }
+    let backend = StorageBackend::Postgres;
+    user_lifecycle(backend, TokenMode::Opaque).await;
+    user_lifecycle(backend, TokenMode::Jwt).await;
+    refresh_rotation(backend).await;
+    session_management(backend, TokenMode::Opaque).await;
+    user_listing(backend).await;
+    soft_delete(backend, TokenMode::Opaque).await;
+    optimistic_concurrency(backend).await;
+    merge_patch_and_replace(backend).await;
+    bulk_import(backend).await;
+    create_many_rollback(backend).await;
+    streaming_export(backend).await;
+}
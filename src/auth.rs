@@ -1,25 +1,243 @@
+use crate::config::Settings;
+use crate::error::ApiError;
 use crate::models::User;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation, errors::Error as JwtError};
+use actix_web::{dev::Payload, Error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ready, Ready};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation, errors::Error as JwtError};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+// Roles derived from usuarios.codperf_usr (see Settings::admin_profile_id)
+pub const ROLE_ADMIN: &str = "admin";
+pub const ROLE_USER: &str = "user";
+
+pub fn role_for_profile(profile: i32, cfg: &Settings) -> &'static str {
+    if profile == cfg.admin_profile_id { ROLE_ADMIN } else { ROLE_USER }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
-    sub: i32,
-    exp: usize,
+    pub sub: i32,
+    // usertoken.TokenID of the session this token belongs to
+    pub sid: i32,
+    pub name: String,
+    pub role: String,
+    pub iss: String,
+    pub aud: String,
+    pub iat: usize,
+    // iat in milliseconds, so a logout-all or delete only cuts off the tokens issued before it, even within
+    // the same second. Tokens from before this claim existed fall back to iat.
+    #[serde(default)]
+    pub iat_ms: u64,
+    pub exp: usize,
+    pub jti: String,
+}
+
+impl Claims {
+    fn issued_at_ms(&self) -> u64 {
+        if self.iat_ms > 0 { self.iat_ms } else { self.iat as u64 * 1000 }
+    }
 }
 
+// Authenticated caller, injected into request extensions by middleware::require_auth
 #[allow(dead_code)]
-pub fn create_token(user: &User, secret: &str) -> anyhow::Result<String> {
-    let start = SystemTime::now();
-    let since_epoch = start.duration_since(UNIX_EPOCH)?.as_secs();
-    let claims = Claims { sub: user.id, exp: (since_epoch + 60 * 60 * 24) as usize };
-    let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes()))?;
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: i32,
+    pub username: String,
+    pub session_id: i32,
+    pub role: String,
+}
+
+impl AuthUser {
+    pub fn is_admin(&self) -> bool {
+        self.role == ROLE_ADMIN
+    }
+
+    // Users manage their own account; admins manage everyone's
+    pub fn can_manage(&self, user_id: i32) -> bool {
+        self.id == user_id || self.is_admin()
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match req.extensions().get::<AuthUser>() {
+            Some(user) => ready(Ok(user.clone())),
+            None => ready(Err(ApiError::Unauthorized.into())),
+        }
+    }
+}
+
+impl From<&Claims> for AuthUser {
+    fn from(c: &Claims) -> Self {
+        AuthUser { id: c.sub, username: c.name.clone(), session_id: c.sid, role: c.role.clone() }
+    }
+}
+
+pub fn now_secs() -> usize {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as usize).unwrap_or(0)
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+pub fn create_token(user: &User, cfg: &Settings, session_id: i32, jti: String) -> anyhow::Result<String> {
+    let iat_ms = now_millis();
+    let iat = (iat_ms / 1000) as usize;
+    let claims = Claims {
+        sub: user.id,
+        sid: session_id,
+        name: user.username.clone(),
+        role: role_for_profile(user.profile, cfg).into(),
+        iss: cfg.jwt_issuer.clone(),
+        aud: cfg.jwt_audience.clone(),
+        iat,
+        iat_ms,
+        exp: iat + (cfg.access_token_ttl_minutes.max(1) as usize) * 60,
+        jti,
+    };
+    let token = encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(cfg.jwt_secret.as_bytes()))?;
     Ok(token)
 }
 
-#[allow(dead_code)]
-pub fn decode_token(token: &str, secret: &str) -> Result<Claims, JwtError> {
-    let token_data = decode::<Claims>(token, &DecodingKey::from_secret(secret.as_bytes()), &Validation::default())?;
+pub fn decode_token(token: &str, cfg: &Settings) -> Result<Claims, JwtError> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_issuer(&[&cfg.jwt_issuer]);
+    validation.set_audience(&[&cfg.jwt_audience]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+    let token_data = decode::<Claims>(token, &DecodingKey::from_secret(cfg.jwt_secret.as_bytes()), &validation)?;
     Ok(token_data.claims)
 }
+
+// In-process revocation list for JWT mode. Entries only need to live until the token would expire anyway,
+// so it stays small; it is not shared between instances and is lost on restart.
+#[derive(Default)]
+pub struct Denylist {
+    inner: Mutex<DenylistInner>,
+}
+
+#[derive(Default)]
+struct DenylistInner {
+    // user id -> (tokens issued at or before this millisecond are revoked, instant after which none of them
+    // can still be valid)
+    users: HashMap<i32, (u64, usize)>,
+    // session id -> instant after which no token of that session can still be valid
+    sessions: HashMap<i32, usize>,
+}
+
+impl Denylist {
+    pub fn revoke_session(&self, session_id: i32, until: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = now_secs();
+        inner.sessions.retain(|_, u| *u > now);
+        inner.sessions.insert(session_id, until);
+    }
+
+    pub fn revoke_user(&self, user_id: i32, until: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = now_secs();
+        inner.users.retain(|_, (_, u)| *u > now);
+        inner.users.insert(user_id, (now_millis(), until));
+    }
+
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.sessions.contains_key(&claims.sid)
+            || inner.users.get(&claims.sub).is_some_and(|(cutoff, _)| claims.issued_at_ms() <= *cutoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> User {
+        User { id: 7, username: "ana".into(), email: None, password_hash: String::new(), profile: 1, version: 1 }
+    }
+
+    #[test]
+    fn jwt_round_trip_carries_claims() {
+        let cfg = Settings::for_tests();
+        let token = create_token(&user(), &cfg, 1, uuid::Uuid::new_v4().to_string()).unwrap();
+        let claims = decode_token(&token, &cfg).unwrap();
+        assert_eq!(claims.sub, 7);
+        assert_eq!(claims.sid, 1);
+        assert_eq!(claims.name, "ana");
+        assert_eq!(claims.role, ROLE_USER);
+        assert_eq!(claims.iss, "backend");
+        assert_eq!(claims.aud, "backend-clients");
+        assert!(claims.exp > claims.iat);
+        assert!(!claims.jti.is_empty());
+    }
+
+    #[test]
+    fn admin_profile_maps_to_admin_role() {
+        let cfg = Settings { admin_profile_id: 2, ..Settings::for_tests() };
+        let admin = User { profile: 2, ..user() };
+        let claims = decode_token(&create_token(&admin, &cfg, 1, uuid::Uuid::new_v4().to_string()).unwrap(), &cfg).unwrap();
+        assert_eq!(claims.role, ROLE_ADMIN);
+        let principal = AuthUser::from(&claims);
+        assert!(principal.is_admin());
+        assert!(principal.can_manage(99));
+    }
+
+    #[test]
+    fn regular_user_manages_only_itself() {
+        let principal = AuthUser { id: 7, username: "ana".into(), session_id: 1, role: ROLE_USER.into() };
+        assert!(!principal.is_admin());
+        assert!(principal.can_manage(7));
+        assert!(!principal.can_manage(8));
+    }
+
+    #[test]
+    fn jwt_rejects_wrong_secret_issuer_or_audience() {
+        let cfg = Settings::for_tests();
+        let token = create_token(&user(), &cfg, 1, uuid::Uuid::new_v4().to_string()).unwrap();
+        assert!(decode_token(&token, &Settings { jwt_secret: "other".into(), ..Settings::for_tests() }).is_err());
+        assert!(decode_token(&token, &Settings { jwt_issuer: "other".into(), ..Settings::for_tests() }).is_err());
+        assert!(decode_token(&token, &Settings { jwt_audience: "other".into(), ..Settings::for_tests() }).is_err());
+    }
+
+    #[test]
+    fn denylist_revokes_session() {
+        let cfg = Settings::for_tests();
+        let a = decode_token(&create_token(&user(), &cfg, 1, uuid::Uuid::new_v4().to_string()).unwrap(), &cfg).unwrap();
+        let b = decode_token(&create_token(&user(), &cfg, 2, uuid::Uuid::new_v4().to_string()).unwrap(), &cfg).unwrap();
+        let denylist = Denylist::default();
+        denylist.revoke_session(1, now_secs() + 600);
+        assert!(denylist.is_revoked(&a));
+        assert!(!denylist.is_revoked(&b));
+    }
+
+    #[test]
+    fn denylist_revokes_every_token_of_user() {
+        let cfg = Settings::for_tests();
+        let a = decode_token(&create_token(&user(), &cfg, 1, uuid::Uuid::new_v4().to_string()).unwrap(), &cfg).unwrap();
+        let denylist = Denylist::default();
+        denylist.revoke_user(7, now_secs() + 600);
+        assert!(denylist.is_revoked(&a));
+        // Logging in again right away, even within the same second, gives a working token
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let again = decode_token(&create_token(&user(), &cfg, 2, uuid::Uuid::new_v4().to_string()).unwrap(), &cfg).unwrap();
+        assert!(!denylist.is_revoked(&again));
+        // Tokens without iat_ms are judged by their iat
+        let legacy = Claims { iat_ms: 0, iat: a.iat - 1, ..a };
+        assert!(denylist.is_revoked(&legacy));
+    }
+
+    #[test]
+    fn denylist_forgets_users_once_their_tokens_expired() {
+        let denylist = Denylist::default();
+        denylist.revoke_user(7, now_secs() - 1);
+        denylist.revoke_user(8, now_secs() + 600);
+        let users: Vec<i32> = denylist.inner.lock().unwrap().users.keys().copied().collect();
+        assert_eq!(users, [8]);
+    }
+}
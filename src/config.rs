@@ -1,8 +1,32 @@
 use dotenvy::dotenv;
 use std::env;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    Mssql,
+    Sqlite,
+    Postgres,
+    // Process-local storage for development and tests; everything is lost on restart
+    Memory,
+}
+
+impl StorageBackend {
+    // Picks the backend from the DATABASE_URL scheme; None for schemes we do not support
+    pub fn from_url(url: &str) -> Option<StorageBackend> {
+        match url.split_once(':').map(|(scheme, _)| scheme) {
+            Some("sqlite") => Some(StorageBackend::Sqlite),
+            Some("postgres") | Some("postgresql") => Some(StorageBackend::Postgres),
+            Some("mssql") | Some("sqlserver") => Some(StorageBackend::Mssql),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DbSettings {
+    pub backend: StorageBackend,
+    // DATABASE_URL; when unset, the SQL Server DSN is built from the DATABASE_* parts
+    pub url: Option<String>,
     pub user: Option<String>,
     pub password: Option<String>,
     pub host: Option<String>,
@@ -15,12 +39,44 @@ pub struct DbSettings {
     pub trust_server_certificate: bool,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenMode {
+    // Random token stored (hashed) in usertoken, validated with a DB lookup
+    Opaque,
+    // Signed JWT verified in-process; revocation goes through the in-process denylist
+    Jwt,
+}
+
+// What the server does at startup when embedded migrations are not all applied (MIGRATE_ON_START)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MigrateOnStart {
+    // Log the pending migrations and serve anyway
+    Warn,
+    // Refuse to start until `backend migrate up` has been run
+    Strict,
+    // Apply pending migrations before serving
+    Apply,
+}
+
 #[derive(Clone)]
 pub struct Settings {
     pub db: DbSettings,
+    pub migrate_on_start: MigrateOnStart,
     pub port: u16,
     pub jwt_secret: String,
+    pub jwt_issuer: String,
+    pub jwt_audience: String,
+    pub token_mode: TokenMode,
+    pub access_token_ttl_minutes: i64,
+    pub refresh_token_ttl_days: i64,
+    // codperf_usr values: the profile treated as admin, and the one given to self-registered users
+    pub admin_profile_id: i32,
+    pub default_profile_id: i32,
     pub concurrency_limit: usize,
+    // Upper bound for ?page_size on GET /users
+    pub max_page_size: u32,
+    // Upper bound for the rows of one POST /users/import
+    pub max_import_rows: usize,
     pub db_query_timeout_secs: u64,
     pub fail_fast: bool,
 }
@@ -28,7 +84,13 @@ pub struct Settings {
 impl Settings {
     pub fn from_env() -> Self {
         dotenv().ok();
+        let url = env::var("DATABASE_URL").ok().filter(|s| !s.is_empty());
         let db = DbSettings {
+            backend: match env::var("DB_BACKEND").ok().as_deref() {
+                Some("memory") | Some("MEMORY") => StorageBackend::Memory,
+                _ => url.as_deref().and_then(StorageBackend::from_url).unwrap_or(StorageBackend::Mssql),
+            },
+            url,
             user: env::var("DATABASE_USERNAME").ok(),
             password: env::var("DATABASE_PASSWORD").ok(),
             host: env::var("DATABASE_HOST").ok(),
@@ -40,12 +102,81 @@ impl Settings {
             encrypt: env::var("DB_ENCRYPT").ok().map(|s| matches!(s.as_str(), "1" | "true" | "True" | "yes")).unwrap_or(true),
             trust_server_certificate: env::var("DB_TRUST_SERVER_CERT").ok().map(|s| matches!(s.as_str(), "1" | "true" | "True" | "yes")).unwrap_or(true),
         };
+        let migrate_on_start = match env::var("MIGRATE_ON_START").ok().as_deref() {
+            Some("strict") => MigrateOnStart::Strict,
+            Some("apply") => MigrateOnStart::Apply,
+            _ => MigrateOnStart::Warn,
+        };
         let port = env::var("PORT").ok().and_then(|s| s.parse().ok()).unwrap_or(8080);
         let jwt_secret = env::var("JWT_SECRET").unwrap_or_else(|_| "secret123".into());
+        let jwt_issuer = env::var("JWT_ISSUER").unwrap_or_else(|_| "backend".into());
+        let jwt_audience = env::var("JWT_AUDIENCE").unwrap_or_else(|_| "backend-clients".into());
+        let token_mode = match env::var("TOKEN_MODE").ok().as_deref() {
+            Some("jwt") | Some("JWT") => TokenMode::Jwt,
+            _ => TokenMode::Opaque,
+        };
+        let access_token_ttl_minutes = env::var("ACCESS_TOKEN_TTL_MINUTES").ok().and_then(|s| s.parse().ok()).unwrap_or(10i64);
+        let refresh_token_ttl_days = env::var("REFRESH_TOKEN_TTL_DAYS").ok().and_then(|s| s.parse().ok()).unwrap_or(30i64);
+        let admin_profile_id = env::var("ADMIN_PROFILE_ID").ok().and_then(|s| s.parse().ok()).unwrap_or(2i32);
+        let default_profile_id = env::var("DEFAULT_PROFILE_ID").ok().and_then(|s| s.parse().ok()).unwrap_or(1i32);
         let concurrency_limit = env::var("CONCURRENCY_LIMIT").ok().and_then(|s| s.parse().ok()).unwrap_or(20usize);
+        let max_page_size = env::var("MAX_PAGE_SIZE").ok().and_then(|s| s.parse().ok()).filter(|n: &u32| *n > 0).unwrap_or(100u32);
+        let max_import_rows = env::var("MAX_IMPORT_ROWS").ok().and_then(|s| s.parse().ok()).filter(|n: &usize| *n > 0).unwrap_or(1000usize);
         let db_query_timeout_secs = env::var("DB_QUERY_TIMEOUT_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(5u64);
         let fail_fast = env::var("FAIL_FAST").ok().map(|s| matches!(s.as_str(), "1" | "true" | "True" | "yes")).unwrap_or(false);
-        Settings { db, port, jwt_secret, concurrency_limit, db_query_timeout_secs, fail_fast }
+        Settings { db, migrate_on_start, port, jwt_secret, jwt_issuer, jwt_audience, token_mode, access_token_ttl_minutes, refresh_token_ttl_days, admin_profile_id, default_profile_id, concurrency_limit, max_page_size, max_import_rows, db_query_timeout_secs, fail_fast }
+    }
+
+    // The from_env defaults, fixed: tests must not depend on the .env or environment of the machine running them
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Settings {
+            db: DbSettings {
+                backend: StorageBackend::Memory,
+                url: None,
+                user: None,
+                password: None,
+                host: None,
+                port: 1433,
+                database: None,
+                min_connections: 1,
+                max_connections: 10,
+                acquire_timeout_secs: 30,
+                encrypt: true,
+                trust_server_certificate: true,
+            },
+            migrate_on_start: MigrateOnStart::Warn,
+            port: 8080,
+            jwt_secret: "test-secret".into(),
+            jwt_issuer: "backend".into(),
+            jwt_audience: "backend-clients".into(),
+            token_mode: TokenMode::Opaque,
+            access_token_ttl_minutes: 10,
+            refresh_token_ttl_days: 30,
+            admin_profile_id: 2,
+            default_profile_id: 1,
+            concurrency_limit: 20,
+            max_page_size: 100,
+            max_import_rows: 1000,
+            db_query_timeout_secs: 5,
+            fail_fast: false,
+        }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_follows_database_url_scheme() {
+        assert_eq!(StorageBackend::from_url("sqlite://./db.sqlite"), Some(StorageBackend::Sqlite));
+        assert_eq!(StorageBackend::from_url("sqlite::memory:"), Some(StorageBackend::Sqlite));
+        assert_eq!(StorageBackend::from_url("postgres://u:p@localhost/crud"), Some(StorageBackend::Postgres));
+        assert_eq!(StorageBackend::from_url("postgresql://localhost/crud"), Some(StorageBackend::Postgres));
+        assert_eq!(StorageBackend::from_url("mssql://sa:pw@127.0.0.1:1433/crud"), Some(StorageBackend::Mssql));
+        assert_eq!(StorageBackend::from_url("mysql://localhost/crud"), None);
+        assert_eq!(StorageBackend::from_url("db.sqlite"), None);
+    }
+}
@@ -1,58 +1,85 @@
+use crate::config::{Settings, StorageBackend};
 use crate::models::{CreateUser, UpdateUser, User};
-use sqlx::{Pool, Mssql, mssql::MssqlPoolOptions, Transaction, Row};
-use bcrypt::{hash, DEFAULT_COST};
+use crate::repository::{self, hash_password, like_pattern, map_unique_violation, non_blank, Conflict, StaleVersion, TokenStore, UserPage, UserQuery, UserRepository};
+use sqlx::{Pool, Mssql, Postgres, Sqlite, mssql::MssqlPoolOptions, Transaction, Row};
+use futures::stream::BoxStream;
 use anyhow::Result;
+use std::sync::Arc;
 use std::time::Duration;
 
-pub async fn init_db(settings: &crate::config::Settings) -> Result<Pool<Mssql>> {
-    // Build connection string for MSSQL
+// The configured storage, connected. Both the server and `backend migrate` start from here.
+pub enum Database {
+    Memory,
+    Mssql(Pool<Mssql>),
+    Sqlite(Pool<Sqlite>),
+    Postgres(Pool<Postgres>),
+}
+
+pub async fn connect(settings: &Settings) -> Result<Database> {
+    let url = settings.db.url.as_deref().unwrap_or_default();
+    match settings.db.backend {
+        StorageBackend::Memory => Ok(Database::Memory),
+        StorageBackend::Mssql => Ok(Database::Mssql(init_db(settings).await?)),
+        StorageBackend::Sqlite => Ok(Database::Sqlite(repository::sqlite::connect(settings, url).await?)),
+        StorageBackend::Postgres => Ok(Database::Postgres(repository::postgres::connect(settings, url).await?)),
+    }
+}
+
+impl Database {
+    pub fn repositories(&self) -> (Arc<dyn UserRepository>, Arc<dyn TokenStore>) {
+        match self {
+            Database::Memory => (Arc::new(repository::MemoryUserRepository::default()), Arc::new(repository::MemoryTokenStore::default())),
+            Database::Mssql(pool) => (Arc::new(repository::MssqlUserRepository::new(pool.clone())), Arc::new(repository::MssqlTokenStore::new(pool.clone()))),
+            Database::Sqlite(pool) => (Arc::new(repository::SqliteUserRepository::new(pool.clone())), Arc::new(repository::SqliteTokenStore::new(pool.clone()))),
+            Database::Postgres(pool) => (Arc::new(repository::PostgresUserRepository::new(pool.clone())), Arc::new(repository::PostgresTokenStore::new(pool.clone()))),
+        }
+    }
+}
+
+pub async fn init_db(settings: &Settings) -> Result<Pool<Mssql>> {
+    // An mssql:// DATABASE_URL wins; otherwise build the connection string from the parts
     let user = settings.db.user.clone().unwrap_or_default();
     let password = settings.db.password.clone().unwrap_or_default();
-    let host = settings.db.host.clone().unwrap_or_else(|| "127.0.0.1".into());
+    let host = settings
+        .db
+        .host
+        .clone()
+        .unwrap_or_else(|| "127.0.0.1".into());
     let port = settings.db.port;
     let database = settings.db.database.clone().unwrap_or_default();
     let encrypt = if settings.db.encrypt { "true" } else { "false" };
-    let trust = if settings.db.trust_server_certificate { "true" } else { "false" };
+    let trust = if settings.db.trust_server_certificate {
+        "true"
+    } else {
+        "false"
+    };
     // Build DSN with configurable flags
-    let conn = format!(
-        "mssql://{}:{}@{}:{}/{}?encrypt={}&trustservercertificate={}",
-        user, password, host, port, database, encrypt, trust
-    );
+    let conn = match &settings.db.url {
+        Some(url) if url.starts_with("mssql:") => url.clone(),
+        _ => format!(
+            "mssql://{}:{}@{}:{}/{}?encrypt={}&trustservercertificate={}",
+            user, password, host, port, database, encrypt, trust
+        ),
+    };
 
-            let pool = MssqlPoolOptions::new()
-                .min_connections(settings.db.min_connections)
-                .max_connections(settings.db.max_connections)
-                .acquire_timeout(Duration::from_secs(settings.db.acquire_timeout_secs))
-                .connect(&conn)
-                .await?;
+    let pool = MssqlPoolOptions::new()
+        .min_connections(settings.db.min_connections)
+        .max_connections(settings.db.max_connections)
+        .acquire_timeout(Duration::from_secs(settings.db.acquire_timeout_secs))
+        .connect(&conn)
+        .await?;
 
-            Ok(pool)
+    Ok(pool)
 }
 
-#[allow(dead_code)]
-pub async fn execute_query(pool: &Pool<Mssql>, query: &str) -> Result<sqlx::mssql::MssqlRow> {
-    let row = sqlx::query(query).fetch_one(pool).await?;
-    Ok(row)
-}
-#[allow(dead_code)]
-pub async fn execute_query_params<T: serde::Serialize + Send + Sync>(pool: &Pool<Mssql>, query: &str, _params: &T) -> Result<sqlx::mssql::MssqlRow> {
-    // sqlx for mssql does not support generic param binding from map; use simple approach: caller should use proper query with args.
-    // For compatibility, we will just execute raw query assuming params are already embedded or use format!. Use a wrapper in handlers.
-    let row = sqlx::query(query).fetch_one(pool).await?;
-    Ok(row)
-}
-
-#[allow(dead_code)]
 pub async fn begin_transaction(pool: &Pool<Mssql>) -> Result<Transaction<'_, Mssql>> {
     let tx = pool.begin().await?;
     Ok(tx)
 }
-#[allow(dead_code)]
 pub async fn commit_transaction(tx: Transaction<'_, Mssql>) -> Result<()> {
     tx.commit().await?;
     Ok(())
 }
-#[allow(dead_code)]
 pub async fn rollback_transaction(tx: Transaction<'_, Mssql>) -> Result<()> {
     tx.rollback().await?;
     Ok(())
@@ -65,86 +92,221 @@ pub async fn getdate(pool: &Pool<Mssql>) -> Result<String> {
     Ok(dt)
 }
 
-// Basic user CRUD using MSSQL stored procedures or inline queries
-pub async fn create_user(pool: &Pool<Mssql>, input: CreateUser) -> Result<User> {
-    let password_hash = hash(&input.password, DEFAULT_COST)?;
-    // Call stored procedure sp_usuarios_insert (signature: nombre, email, codperf, contrasena, usercrea, usermod, fechcrea, fechmod)
-    let _ = sqlx::query(
+// Every timestamp the app writes comes from here (UTC, formatted for datetime columns) rather than
+// from the server's GETDATE(), so expiry comparisons and audit columns share one clock
+pub fn utc_timestamp(dt: chrono::DateTime<chrono::Utc>) -> String {
+    dt.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+pub fn utc_now() -> String {
+    utc_timestamp(chrono::Utc::now())
+}
+
+// Basic user CRUD using MSSQL stored procedures or inline queries.
+// `actor` is the authenticated user performing the change (0 for self-registration) and goes to usercrea/usermod.
+pub async fn create_user(pool: &Pool<Mssql>, input: CreateUser, profile: i32, actor: i32) -> Result<User> {
+    let password_hash = hash_password(input.password.clone()).await?;
+    let mut tx = begin_transaction(pool).await?;
+    let rec = insert_user(&mut tx, input, password_hash, profile, actor, &utc_now()).await?;
+    commit_transaction(tx).await?;
+    Ok(rec)
+}
+
+// POST /users/import: every insert shares one transaction, so a failing row leaves no other row behind
+pub async fn create_users(pool: &Pool<Mssql>, users: Vec<(CreateUser, i32)>, actor: i32) -> Result<Vec<User>> {
+    let hashes = repository::hash_passwords(&users).await?;
+    let now = utc_now();
+    let mut tx = begin_transaction(pool).await?;
+    let mut created = Vec::with_capacity(users.len());
+    for ((input, profile), password_hash) in users.into_iter().zip(hashes) {
+        match insert_user(&mut tx, input, password_hash, profile, actor, &now).await {
+            Ok(rec) => created.push(rec),
+            Err(e) => {
+                rollback_transaction(tx).await?;
+                return Err(e);
+            }
+        }
+    }
+    commit_transaction(tx).await?;
+    Ok(created)
+}
+
+async fn insert_user(tx: &mut Transaction<'_, Mssql>, input: CreateUser, password_hash: String, profile: i32, actor: i32, now: &str) -> Result<User> {
+    // Call stored procedure sp_usuarios_insert (signature: nombre, email, codperf, contrasena, usercrea, usermod, fechcrea, fechmod).
+    // It selects SCOPE_IDENTITY() (migration 0003); reading the row back in the same transaction returns exactly this insert.
+    let id: i32 = sqlx::query_scalar(
         "EXEC sp_usuarios_insert @nombre_usr = @p1, @email_usr = @p2, @codperf_usr = @p3, @contrasena_usr = @p4, @usercrea = @p5, @usermod = @p6, @fechcrea = @p7, @fechmod = @p8"
     )
-    .bind(input.username.clone())
-    .bind(input.email.clone().unwrap_or_default())
-    .bind(1i32)
+    .bind(input.username)
+    .bind(input.email)
+    .bind(profile)
     .bind(password_hash)
-    .bind(0i32)
-    .bind(0i32)
-    .bind(chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string())
-    .bind(chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string())
-    .execute(pool)
-    .await?;
+    .bind(actor)
+    .bind(actor)
+    .bind(now)
+    .bind(now)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(map_unique_violation)?;
 
-    // Return the created user by selecting last inserted by name (assuming unique nombre_usr)
     let rec = sqlx::query_as::<_, User>(
-        "SELECT codusr_usr, nombre_usr, email_usr, contrasena_usr FROM usuarios WHERE nombre_usr = @p1"
+        "SELECT codusr_usr, nombre_usr, email_usr, contrasena_usr, codperf_usr, row_version FROM usuarios WHERE codusr_usr = @p1"
     )
-    .bind(input.username)
-    .fetch_one(pool)
+    .bind(id)
+    .fetch_one(&mut *tx)
     .await?;
     Ok(rec)
 }
 
-pub async fn list_users(pool: &Pool<Mssql>) -> Result<Vec<User>> {
-    let users = sqlx::query_as::<_, User>("SELECT codusr_usr, nombre_usr, email_usr, contrasena_usr FROM usuarios").fetch_all(pool).await?;
-    Ok(users)
+// Live users matching UserQuery's filters: @p1 username pattern, @p2 email pattern, @p3 profile
+const USER_FILTER: &str = "WHERE deleted_at IS NULL AND (@p1 IS NULL OR nombre_usr LIKE @p1 ESCAPE '\\') AND (@p2 IS NULL OR email_usr LIKE @p2 ESCAPE '\\') AND (@p3 IS NULL OR codperf_usr = @p3)";
+
+// '[' opens a character class in T-SQL LIKE
+fn mssql_like_pattern(value: &str) -> String {
+    like_pattern(value).replace('[', "\\[")
+}
+
+pub async fn search_users(pool: &Pool<Mssql>, query: &UserQuery) -> Result<UserPage> {
+    let username = query.username.as_deref().map(mssql_like_pattern);
+    let email = query.email.as_deref().map(mssql_like_pattern);
+    let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT_BIG(*) FROM usuarios {}", USER_FILTER))
+        .bind(username.clone())
+        .bind(email.clone())
+        .bind(query.profile)
+        .fetch_one(pool)
+        .await?;
+    let users = sqlx::query_as::<_, User>(&format!(
+        "SELECT codusr_usr, nombre_usr, email_usr, contrasena_usr, codperf_usr, row_version FROM usuarios {} ORDER BY {} OFFSET @p4 ROWS FETCH NEXT @p5 ROWS ONLY",
+        USER_FILTER,
+        query.order_by()
+    ))
+    .bind(username)
+    .bind(email)
+    .bind(query.profile)
+    .bind(query.offset)
+    .bind(query.limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(UserPage { users, total })
+}
+
+// GET /users/export: the rows are read on a task of their own while the response streams them
+pub fn export_users(pool: Pool<Mssql>, query: UserQuery) -> BoxStream<'static, Result<User>> {
+    repository::export_stream(move |tx| async move {
+        let sql = format!(
+            "SELECT codusr_usr, nombre_usr, email_usr, contrasena_usr, codperf_usr, row_version FROM usuarios {} ORDER BY {}",
+            USER_FILTER,
+            query.order_by()
+        );
+        let rows = sqlx::query_as::<_, User>(&sql)
+            .bind(query.username.as_deref().map(mssql_like_pattern))
+            .bind(query.email.as_deref().map(mssql_like_pattern))
+            .bind(query.profile)
+            .fetch(&pool);
+        repository::forward_rows(rows, tx).await;
+    })
 }
 
 pub async fn find_by_username(pool: &Pool<Mssql>, username: &str) -> Result<Option<User>> {
-    let u = sqlx::query_as::<_, User>("SELECT codusr_usr, nombre_usr, email_usr, contrasena_usr FROM usuarios WHERE nombre_usr = @p1 OR email_usr = @p1")
+    let u = sqlx::query_as::<_, User>("SELECT codusr_usr, nombre_usr, email_usr, contrasena_usr, codperf_usr, row_version FROM usuarios WHERE (nombre_usr = @p1 OR email_usr = @p1) AND deleted_at IS NULL")
         .bind(username)
         .fetch_optional(pool)
         .await?;
     Ok(u)
 }
 
+pub async fn find_conflict(pool: &Pool<Mssql>, username: Option<&str>, email: Option<&str>, except: Option<i32>) -> Result<Option<Conflict>> {
+    let kind: Option<i32> = sqlx::query_scalar(
+        "SELECT TOP 1 CASE WHEN nombre_usr = @p1 THEN 1 ELSE 2 END AS kind FROM usuarios \
+         WHERE (nombre_usr = @p1 OR email_usr = @p2) AND codusr_usr <> @p3 ORDER BY kind"
+    )
+    .bind(username)
+    .bind(non_blank(email))
+    .bind(except.unwrap_or(0))
+    .fetch_optional(pool)
+    .await?;
+    Ok(kind.map(|k| if k == 1 { Conflict::Username } else { Conflict::Email }))
+}
+
 pub async fn get_user(pool: &Pool<Mssql>, user_id: i32) -> Result<Option<User>> {
-    let user = sqlx::query_as::<_, User>("SELECT codusr_usr, nombre_usr, email_usr, contrasena_usr FROM usuarios WHERE codusr_usr = @p1")
+    let user = sqlx::query_as::<_, User>("SELECT codusr_usr, nombre_usr, email_usr, contrasena_usr, codperf_usr, row_version FROM usuarios WHERE codusr_usr = @p1 AND deleted_at IS NULL")
         .bind(user_id)
         .fetch_optional(pool)
         .await?;
     Ok(user)
 }
 
-pub async fn update_user(pool: &Pool<Mssql>, user_id: i32, input: UpdateUser) -> Result<Option<User>> {
+pub async fn update_user(pool: &Pool<Mssql>, user_id: i32, input: UpdateUser, actor: i32, expected_version: Option<i32>) -> Result<Option<User>> {
     // Use the stored procedure sp_usuarios_update if available
     let current = get_user(pool, user_id).await?;
     if current.is_none() {
         return Ok(None);
     }
     let cur = current.unwrap();
+    if expected_version.is_some_and(|v| v != cur.version) {
+        return Err(StaleVersion.into());
+    }
     let new_username = input.username.unwrap_or(cur.username);
-    let new_email = input.email.unwrap_or(cur.email.unwrap_or_default()); // Silence unused new_email
-    let new_password = if let Some(pw) = input.password { hash(&pw, DEFAULT_COST)? } else { cur.password_hash };
-
-    // Updated sp_usuarios_update signature: @codusr_usr, @nombre_usr, @email_usr, @codperf_usr, @contrasena_usr, @usercrea, @usermod, @fechcrea, @fechmod
-    let _ = sqlx::query(
-        "EXEC sp_usuarios_update @codusr_usr = @p1, @nombre_usr = @p2, @email_usr = @p3, @codperf_usr = @p4, @contrasena_usr = @p5, @usercrea = @p6, @usermod = @p7, @fechcrea = @p8, @fechmod = @p9"
+    let new_email = input.email.unwrap_or(cur.email);
+    let new_profile = input.profile.unwrap_or(cur.profile);
+    let new_password = match input.password {
+        Some(pw) => hash_password(pw).await?,
+        None => cur.password_hash,
+    };
+    // sp_usuarios_update signature: @codusr_usr, @nombre_usr, @email_usr, @codperf_usr, @contrasena_usr, @usermod, @fechmod
+    // plus @row_version (migration 0006), which makes the write conditional on the version when it is not NULL.
+    // usercrea/fechcrea are left as they are (migration 0009).
+    // The procedure selects @@ROWCOUNT (migration 0002); zero means the user was deleted or changed after the read above.
+    let affected: i32 = sqlx::query_scalar(
+        "EXEC sp_usuarios_update @codusr_usr = @p1, @nombre_usr = @p2, @email_usr = @p3, @codperf_usr = @p4, @contrasena_usr = @p5, @usermod = @p6, @fechmod = @p7, @row_version = @p8"
     )
     .bind(user_id)
     .bind(new_username)
-    .bind(new_email.clone())
-    .bind(1i32)
+    .bind(new_email)
+    .bind(new_profile)
     .bind(new_password)
-    .bind(0i32)
-    .bind(0i32)
-    .bind(chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string())
-    .bind(chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string())
-    .execute(pool)
-    .await?;
-    Ok(get_user(pool, user_id).await?)
+    .bind(actor)
+    .bind(utc_now())
+    .bind(expected_version)
+    .fetch_one(pool)
+    .await
+    .map_err(map_unique_violation)?;
+    if affected == 0 {
+        return match get_user(pool, user_id).await? {
+            Some(_) if expected_version.is_some() => Err(StaleVersion.into()),
+            _ => Ok(None),
+        };
+    }
+    get_user(pool, user_id).await
+}
+
+// Soft delete since migration 0005: the procedure stamps deleted_at and the audit columns
+pub async fn delete_user(pool: &Pool<Mssql>, user_id: i32, actor: i32) -> Result<bool> {
+    let affected: i32 = sqlx::query_scalar("EXEC sp_usuarios_delete @codusr_usr = @p1, @usermod = @p2, @fechmod = @p3")
+        .bind(user_id)
+        .bind(actor)
+        .bind(utc_now())
+        .fetch_one(pool)
+        .await?;
+    Ok(affected > 0)
+}
+
+pub async fn restore_user(pool: &Pool<Mssql>, user_id: i32, actor: i32) -> Result<Option<User>> {
+    let affected: i32 = sqlx::query_scalar("EXEC sp_usuarios_restore @codusr_usr = @p1, @usermod = @p2, @fechmod = @p3")
+        .bind(user_id)
+        .bind(actor)
+        .bind(utc_now())
+        .fetch_one(pool)
+        .await?;
+    if affected == 0 {
+        return Ok(None);
+    }
+    get_user(pool, user_id).await
 }
 
-pub async fn delete_user(pool: &Pool<Mssql>, user_id: i32) -> Result<bool> {
-    let _ = sqlx::query("EXEC sp_usuarios_delete @codusr_usr = @p1").bind(user_id).execute(pool).await?;
-    Ok(true)
+// Also removes the user's usertoken and refreshtoken rows, inside the procedure's transaction
+pub async fn purge_user(pool: &Pool<Mssql>, user_id: i32) -> Result<bool> {
+    let affected: i32 = sqlx::query_scalar("EXEC sp_usuarios_purge @codusr_usr = @p1").bind(user_id).fetch_one(pool).await?;
+    Ok(affected > 0)
 }
 
@@ -0,0 +1,223 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use crate::repository::{Conflict, StaleVersion};
+use serde::Serialize;
+use std::fmt;
+use validator::ValidationErrors;
+
+// Every error a handler or middleware returns to a client. Rendered as application/problem+json (RFC 7807)
+// with a stable `code`; internal errors are logged with a correlation id and only that id reaches the client.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    // Every invalid field of the payload, rendered as `errors` so clients can show them inline
+    Validation(ValidationErrors),
+    Unauthorized,
+    InvalidCredentials,
+    InvalidRefreshToken,
+    Forbidden,
+    NotFound,
+    Conflict(Conflict),
+    // If-Match did not match the current ETag of the resource
+    PreconditionFailed,
+    Internal(anyhow::Error),
+}
+
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+// Also used per row by the import report
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, code: &str, message: impl Into<String>) -> Self {
+        FieldError { field: field.to_string(), code: code.to_string(), message: message.into() }
+    }
+}
+
+pub fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    let mut out: Vec<FieldError> = errors
+        .field_errors()
+        .into_iter()
+        .flat_map(|(field, errs)| {
+            errs.iter().map(move |e| FieldError {
+                field: field.to_string(),
+                code: e.code.to_string(),
+                message: e.message.as_ref().map(|m| m.to_string()).unwrap_or_else(|| e.code.to_string()),
+            })
+        })
+        .collect();
+    out.sort_by(|a, b| a.field.cmp(&b.field));
+    out
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::InvalidRefreshToken => "invalid_refresh_token",
+            ApiError::Forbidden => "forbidden",
+            ApiError::NotFound => "not_found",
+            ApiError::Conflict(c) => c.code(),
+            ApiError::PreconditionFailed => "precondition_failed",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "Bad request",
+            ApiError::Validation(_) => "Unprocessable entity",
+            ApiError::Unauthorized | ApiError::InvalidCredentials | ApiError::InvalidRefreshToken => "Unauthorized",
+            ApiError::Forbidden => "Forbidden",
+            ApiError::NotFound => "Not found",
+            ApiError::Conflict(_) => "Conflict",
+            ApiError::PreconditionFailed => "Precondition failed",
+            ApiError::Internal(_) => "Internal server error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(detail) => write!(f, "{}", detail),
+            ApiError::Validation(_) => write!(f, "the request has invalid fields"),
+            ApiError::Unauthorized => write!(f, "a valid bearer token is required"),
+            ApiError::InvalidCredentials => write!(f, "invalid username or password"),
+            ApiError::InvalidRefreshToken => write!(f, "the refresh token is invalid, expired or already used"),
+            ApiError::Forbidden => write!(f, "not allowed for this user"),
+            ApiError::NotFound => write!(f, "resource not found"),
+            ApiError::Conflict(c) => write!(f, "{}", c),
+            ApiError::PreconditionFailed => write!(f, "the resource was modified since it was read, fetch it again"),
+            // Never shown to clients, see error_response
+            ApiError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(e: ValidationErrors) -> Self {
+        ApiError::Validation(e)
+    }
+}
+
+// Conflicts and stale versions raised by the repositories keep their meaning; anything else is internal
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        if let Some(c) = e.downcast_ref::<Conflict>() {
+            return ApiError::Conflict(*c);
+        }
+        if e.is::<StaleVersion>() {
+            return ApiError::PreconditionFailed;
+        }
+        ApiError::Internal(e)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unauthorized | ApiError::InvalidCredentials | ApiError::InvalidRefreshToken => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let (detail, correlation_id) = match self {
+            ApiError::Internal(e) => {
+                let id = uuid::Uuid::new_v4().to_string();
+                eprintln!("[{}] internal error: {:?}", id, e);
+                (format!("unexpected error, reference {}", id), Some(id))
+            }
+            other => (other.to_string(), None),
+        };
+        let problem = Problem {
+            kind: format!("/problems/{}", self.code().replace('_', "-")),
+            title: self.title(),
+            status: status.as_u16(),
+            detail,
+            code: self.code(),
+            correlation_id,
+            errors: match self {
+                ApiError::Validation(e) => field_errors(e),
+                _ => Vec::new(),
+            },
+        };
+        HttpResponse::build(status).content_type("application/problem+json").json(problem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use serde_json::Value;
+
+    async fn render(e: ApiError) -> (StatusCode, String, Value) {
+        let res = e.error_response();
+        let content_type = res.headers().get("content-type").unwrap().to_str().unwrap().to_string();
+        let body = to_bytes(res.into_body()).await.unwrap();
+        (e.status_code(), content_type, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn renders_problem_json() {
+        let (status, content_type, body) = render(ApiError::Conflict(Conflict::Email)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(content_type, "application/problem+json");
+        assert_eq!(body["type"], "/problems/email-taken");
+        assert_eq!(body["status"], 409);
+        assert_eq!(body["code"], "email_taken");
+        assert!(body.get("correlation_id").is_none());
+    }
+
+    #[actix_web::test]
+    async fn internal_errors_do_not_leak() {
+        let (status, _, body) = render(anyhow::anyhow!("Invalid object name 'dbo.usuarios'").into()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["code"], "internal_error");
+        let id = body["correlation_id"].as_str().unwrap();
+        assert!(body["detail"].as_str().unwrap().contains(id));
+        assert!(!body.to_string().contains("usuarios"));
+    }
+
+    #[actix_web::test]
+    async fn conflicts_survive_anyhow() {
+        let e: ApiError = anyhow::Error::from(Conflict::Username).into();
+        assert_eq!(e.code(), "username_taken");
+    }
+
+    #[actix_web::test]
+    async fn stale_versions_are_precondition_failures() {
+        let (status, _, body) = render(anyhow::Error::from(StaleVersion).into()).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(body["type"], "/problems/precondition-failed");
+    }
+}
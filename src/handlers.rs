@@ -1,130 +1,422 @@
-use actix_web::{web, HttpResponse, Responder};
-use sqlx::Pool;
-use sqlx::Mssql;
-use crate::models::{CreateUser, LoginRequest, LoginResponse, UpdateUser};
-use crate::db;
-use crate::token::TokenService;
-use bcrypt::verify;
+use actix_web::http::header::{EntityTag, ETag, Header, IfMatch, IfNoneMatch};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use crate::models::{Availability, AvailabilityQuery, CreateUser, DeleteUserQuery, ExportFormat, ExportQuery, ImportQuery, ImportReport, ImportRowReport, ImportStatus, ImportUser, LoginRequest, LoginResponse, PageLinks, RefreshRequest, ReplaceUser, SessionView, UpdateUser, User, UserListPage, UserListQuery, UserView};
+use crate::auth::{self, AuthUser, Denylist};
+use crate::config::{Settings, TokenMode};
+use crate::error::{field_errors, ApiError, FieldError};
+use crate::repository::{non_blank, verify_password, TokenStore, UserQuery, UserRepository, UserSort};
+use crate::refresh::{RefreshOutcome, RefreshTokenService};
+use crate::token::{NewSession, TokenService};
 use futures::stream::{self, StreamExt};
 use futures::future::try_join_all;
+use std::collections::HashSet;
 use std::time::Duration;
+use validator::Validate;
 use tokio::time::timeout;
 
-pub async fn login(pool: web::Data<Pool<Mssql>>, cfg: web::Data<crate::config::Settings>, body: web::Json<LoginRequest>) -> impl Responder {
-    match db::find_by_username(&pool, &body.username).await {
-        Ok(Some(user)) => {
-            if verify(&body.password, &user.password_hash).unwrap_or(false) {
-                match TokenService::generate_token(&pool, &user.id.to_string(), false, None, &cfg.jwt_secret).await {
-                    Ok(token) => HttpResponse::Ok().json(LoginResponse { token }),
-                    Err(e) => {
-                        eprintln!("token gen err: {}", e);
-                        HttpResponse::InternalServerError().finish()
-                    }
-                }
-            } else {
-                HttpResponse::Unauthorized().body("Invalid credentials")
-            }
+fn new_session(req: &HttpRequest, device_name: Option<String>) -> NewSession {
+    NewSession {
+        family_id: uuid::Uuid::new_v4().to_string(),
+        device_name,
+        user_agent: req.headers().get("user-agent").and_then(|v| v.to_str().ok()).map(str::to_string),
+        ip_address: req.peer_addr().map(|a| a.ip().to_string()),
+    }
+}
+
+pub async fn login(users: web::Data<dyn UserRepository>, tokens: web::Data<dyn TokenStore>, cfg: web::Data<Settings>, req: HttpRequest, body: web::Json<LoginRequest>) -> Result<HttpResponse, ApiError> {
+    body.validate()?;
+    let user = match users.find_by_username(&body.username).await? {
+        Some(user) if verify_password(body.password.clone(), user.password_hash.clone()).await? => user,
+        _ => return Err(ApiError::InvalidCredentials),
+    };
+    let session = new_session(&req, body.0.device_name);
+    let (token, _) = TokenService::issue_access_token(tokens.get_ref(), &cfg, &user, &session).await?;
+    let refresh_token = RefreshTokenService::issue(tokens.get_ref(), user.id, &session.family_id, cfg.refresh_token_ttl_days).await?;
+    Ok(HttpResponse::Ok().json(LoginResponse { token, refresh_token }))
+}
+
+// Rotates the refresh token and renews the access token of the same session;
+// replaying an already rotated token revokes its whole family
+pub async fn refresh_token(users: web::Data<dyn UserRepository>, tokens: web::Data<dyn TokenStore>, cfg: web::Data<Settings>, body: web::Json<RefreshRequest>) -> Result<HttpResponse, ApiError> {
+    let (user_id, family_id, refresh_token) = match RefreshTokenService::rotate(tokens.get_ref(), &body.refresh_token, cfg.refresh_token_ttl_days).await? {
+        RefreshOutcome::Rotated { user_id, family_id, refresh_token } => (user_id, family_id, refresh_token),
+        RefreshOutcome::Invalid => return Err(ApiError::InvalidRefreshToken),
+        RefreshOutcome::Reused => {
+            eprintln!("refresh token reuse detected, family revoked");
+            return Err(ApiError::InvalidRefreshToken);
+        }
+    };
+    let user = users.get(user_id).await?.ok_or(ApiError::InvalidRefreshToken)?;
+    match TokenService::renew_session(tokens.get_ref(), &cfg, &user, &family_id).await? {
+        Some(token) => Ok(HttpResponse::Ok().json(LoginResponse { token, refresh_token })),
+        None => {
+            // The session was revoked; its refresh family must not outlive it
+            tokens.revoke_refresh_family(&family_id).await?;
+            Err(ApiError::InvalidRefreshToken)
         }
-        Ok(None) => HttpResponse::Unauthorized().body("Invalid credentials"),
-        Err(e) => {
-            eprintln!("db err: {}", e);
-            HttpResponse::InternalServerError().finish()
+    }
+}
+
+// Needs only the bearer token, not a valid session, so repeating the call still returns 204.
+// Ends the whole session, so its refresh token stops working too.
+pub async fn logout(tokens: web::Data<dyn TokenStore>, cfg: web::Data<Settings>, denylist: web::Data<Denylist>, req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    let token = TokenService::extract_token_from_header(&req).ok_or(ApiError::Unauthorized)?;
+    if cfg.token_mode == TokenMode::Jwt {
+        // An expired or forged JWT is already unusable, so there is nothing left to revoke. Every refresh mints
+        // another JWT for the same session, so deny the whole session rather than just the presented one.
+        if let Ok(claims) = auth::decode_token(&token, &cfg) {
+            denylist.revoke_session(claims.sid, deny_until(&cfg));
+            tokens.revoke_session(claims.sub, claims.sid).await?;
         }
+    } else {
+        TokenService::revoke_token(tokens.get_ref(), &token).await?;
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// How long a denylist entry has to outlive the JWTs it covers
+fn deny_until(cfg: &Settings) -> usize {
+    auth::now_secs() + (cfg.access_token_ttl_minutes.max(1) as usize) * 60 + 60
+}
+
+pub async fn logout_all(tokens: web::Data<dyn TokenStore>, cfg: web::Data<Settings>, denylist: web::Data<Denylist>, user: AuthUser) -> Result<HttpResponse, ApiError> {
+    denylist.revoke_user(user.id, deny_until(&cfg));
+    tokens.revoke_all_sessions(user.id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn list_sessions(tokens: web::Data<dyn TokenStore>, user: AuthUser) -> Result<HttpResponse, ApiError> {
+    let sessions = tokens.list_sessions(user.id).await?;
+    let sessions: Vec<SessionView> = sessions.into_iter().map(|s| SessionView { current: s.id == user.session_id, ..s }).collect();
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+pub async fn revoke_session(tokens: web::Data<dyn TokenStore>, cfg: web::Data<Settings>, denylist: web::Data<Denylist>, user: AuthUser, path: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let session_id = path.into_inner();
+    if !tokens.revoke_session(user.id, session_id).await? {
+        return Err(ApiError::NotFound);
     }
+    // JWTs of that session stay verifiable until they expire, so deny them in-process as well
+    denylist.revoke_session(session_id, deny_until(&cfg));
+    Ok(HttpResponse::NoContent().finish())
 }
 
-pub async fn create_user(pool: web::Data<Pool<Mssql>>, body: web::Json<CreateUser>) -> impl Responder {
-    match db::create_user(&pool, body.0).await {
-        Ok(user) => HttpResponse::Created().json(user),
-        Err(e) => HttpResponse::BadRequest().body(format!("Err: {}", e)),
+// Self-registration always gets the default profile; admins change it afterwards with PUT.
+// The creator is the authenticated caller, or 0 for an anonymous signup.
+pub async fn create_user(users: web::Data<dyn UserRepository>, cfg: web::Data<Settings>, caller: Option<AuthUser>, body: web::Json<CreateUser>) -> Result<HttpResponse, ApiError> {
+    body.validate()?;
+    let actor = caller.map_or(0, |c| c.id);
+    let user = users.create(body.0, cfg.default_profile_id, actor).await?;
+    Ok(HttpResponse::Created().json(UserView::from(user)))
+}
+
+// Public, for the signup form: each value is checked on its own so the client can flag the right field
+pub async fn availability(users: web::Data<dyn UserRepository>, query: web::Query<AvailabilityQuery>) -> Result<HttpResponse, ApiError> {
+    let username = query.username.as_deref().filter(|u| !u.is_empty());
+    let email = non_blank(query.email.as_deref());
+    if username.is_none() && email.is_none() {
+        return Err(ApiError::BadRequest("username or email required".into()));
+    }
+    let mut result = Availability { username: None, email: None };
+    if username.is_some() {
+        result.username = Some(users.find_conflict(username, None, None).await?.is_none());
     }
+    if email.is_some() {
+        result.email = Some(users.find_conflict(None, email, None).await?.is_none());
+    }
+    Ok(HttpResponse::Ok().json(result))
+}
+
+const DEFAULT_PAGE_SIZE: u32 = 20;
+
+// The filters and sort shared by GET /users and GET /users/export
+fn user_query(query: &UserListQuery, offset: i64, limit: i64) -> Result<UserQuery, ApiError> {
+    Ok(UserQuery {
+        username: query.username.clone().filter(|u| !u.is_empty()),
+        email: query.email.clone().filter(|e| !e.is_empty()),
+        profile: query.profile,
+        sort: UserSort::parse_list(query.sort.as_deref().unwrap_or_default()).map_err(ApiError::BadRequest)?,
+        offset,
+        limit,
+    })
+}
+
+// (page, page_size) requested, with page_size capped by MAX_PAGE_SIZE
+fn page_of(query: &UserListQuery, cfg: &Settings) -> (u32, u32) {
+    (query.page.unwrap_or(1).max(1), query.page_size.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, cfg.max_page_size))
+}
+
+// Filters match substrings of username/email and the exact profile
+pub async fn list_users(users: web::Data<dyn UserRepository>, cfg: web::Data<Settings>, req: HttpRequest, query: web::Query<UserListQuery>) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    let (page, page_size) = page_of(&query, &cfg);
+    let result = users.search(&user_query(&query, (page as i64 - 1) * page_size as i64, page_size as i64)?).await?;
+    let total_pages = (result.total + page_size as i64 - 1) / page_size as i64;
+    let link = |page: u32| {
+        let q = UserListQuery { page: Some(page), page_size: Some(page_size), ..query.clone() };
+        format!("{}?{}", req.path(), serde_urlencoded::to_string(&q).unwrap_or_default())
+    };
+    Ok(HttpResponse::Ok().json(UserListPage {
+        items: result.users.into_iter().map(UserView::from).collect(),
+        page,
+        page_size,
+        total: result.total,
+        total_pages,
+        links: PageLinks {
+            current: link(page),
+            next: ((page as i64) < total_pages).then(|| link(page + 1)),
+            prev: (page > 1).then(|| link(page - 1)),
+        },
+    }))
+}
+
+fn etag(user: &User) -> EntityTag {
+    EntityTag::new_strong(user.version.to_string())
 }
 
-pub async fn list_users(pool: web::Data<Pool<Mssql>>) -> impl Responder {
-    match db::list_users(&pool).await {
-        Ok(users) => HttpResponse::Ok().json(users),
-        Err(_) => HttpResponse::InternalServerError().finish(),
+// None when the header is absent; a malformed one is a bad request rather than "no precondition"
+fn precondition<H: Header>(req: &HttpRequest) -> Result<Option<H>, ApiError> {
+    if !req.headers().contains_key(H::name()) {
+        return Ok(None);
     }
+    H::parse(req).map(Some).map_err(|_| ApiError::BadRequest(format!("invalid {} header", H::name())))
 }
 
-pub async fn get_user(pool: web::Data<Pool<Mssql>>, path: web::Path<i32>) -> impl Responder {
+pub async fn get_user(users: web::Data<dyn UserRepository>, user: AuthUser, req: HttpRequest, path: web::Path<i32>) -> Result<HttpResponse, ApiError> {
     let id = path.into_inner();
-    match db::get_user(&pool, id).await {
-        Ok(Some(u)) => HttpResponse::Ok().json(u),
-        Ok(None) => HttpResponse::NotFound().body("Not found"),
-        Err(_) => HttpResponse::InternalServerError().finish(),
+    if !user.can_manage(id) {
+        return Err(ApiError::Forbidden);
     }
+    let user = users.get(id).await?.ok_or(ApiError::NotFound)?;
+    let tag = etag(&user);
+    let fresh = match precondition::<IfNoneMatch>(&req)? {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|t| t.weak_eq(&tag)),
+        None => false,
+    };
+    if fresh {
+        return Ok(HttpResponse::NotModified().insert_header(ETag(tag)).finish());
+    }
+    Ok(HttpResponse::Ok().insert_header(ETag(tag)).json(UserView::from(user)))
 }
 
-pub async fn update_user(pool: web::Data<Pool<Mssql>>, path: web::Path<i32>, body: web::Json<UpdateUser>) -> impl Responder {
+// PUT: full replacement, a missing email clears it
+pub async fn update_user(users: web::Data<dyn UserRepository>, user: AuthUser, req: HttpRequest, path: web::Path<i32>, body: web::Json<ReplaceUser>) -> Result<HttpResponse, ApiError> {
     let id = path.into_inner();
-    match db::update_user(&pool, id, body.0).await {
-        Ok(Some(u)) => HttpResponse::Ok().json(u),
-        Ok(None) => HttpResponse::NotFound().body("Not found"),
-        Err(e) => HttpResponse::BadRequest().body(format!("Err: {}", e)),
+    if !user.can_manage(id) {
+        return Err(ApiError::Forbidden);
     }
+    body.validate()?;
+    write_user(users.get_ref(), &user, &req, id, body.0.into()).await
 }
 
-pub async fn delete_user(pool: web::Data<Pool<Mssql>>, path: web::Path<i32>) -> impl Responder {
+// PATCH with a JSON merge patch (application/merge-patch+json, plain application/json is accepted too)
+pub async fn patch_user(users: web::Data<dyn UserRepository>, user: AuthUser, req: HttpRequest, path: web::Path<i32>, body: web::Json<UpdateUser>) -> Result<HttpResponse, ApiError> {
     let id = path.into_inner();
-    match db::delete_user(&pool, id).await {
-        Ok(true) => HttpResponse::NoContent().finish(),
-        Ok(false) => HttpResponse::NotFound().body("Not found"),
-        Err(_) => HttpResponse::InternalServerError().finish(),
-    }
-}
-
-// Example endpoint demonstrating concurrent data load using join_all (Promise.all equivalent)
-pub async fn load_concurrent(pool: web::Data<Pool<Mssql>>, cfg: web::Data<crate::config::Settings>) -> impl Responder {
-    // For demo, we will fetch the list of users and then fetch each user individually concurrently
-    match db::list_users(&pool).await {
-        Ok(users) => {
-            let concurrency_limit = cfg.concurrency_limit;
-            let timeout_secs = cfg.db_query_timeout_secs;
-            let fail_fast = cfg.fail_fast;
-
-            // Build a vector of futures where each db::get_user is wrapped with a timeout
-            let futures_vec = users.into_iter().map(|u| {
-                let pool = pool.clone();
-                async move {
-                    // apply per-query timeout
-                    let fut = db::get_user(&pool, u.id);
-                    match timeout(Duration::from_secs(timeout_secs), fut).await {
-                        Ok(inner_res) => inner_res, // Result<Option<User>, sqlx::Error>
-                            Err(_) => Err(anyhow::anyhow!("timeout")),
-                    }
-                }
-            }).collect::<Vec<_>>();
-
-            // If fail_fast is desired, use try_join_all which returns Err on first Err.
-            if fail_fast {
-                match try_join_all(futures_vec).await {
-                    Ok(results) => {
-                        let mut out = Vec::new();
-                        for r in results {
-                            if let Some(u) = r {
-                                out.push(u);
-                            }
-                        }
-                        return HttpResponse::Ok().json(out);
-                    }
-                    Err(e) => return HttpResponse::InternalServerError().body(format!("Err: {}", e)),
-                }
+    if !user.can_manage(id) {
+        return Err(ApiError::Forbidden);
+    }
+    body.validate()?;
+    write_user(users.get_ref(), &user, &req, id, body.0).await
+}
+
+// With If-Match the write only happens if the user still has one of the given versions; the version check
+// is repeated inside the UPDATE so a concurrent writer between the read and the write is also caught.
+async fn write_user(users: &dyn UserRepository, user: &AuthUser, req: &HttpRequest, id: i32, input: UpdateUser) -> Result<HttpResponse, ApiError> {
+    let current = users.get(id).await?;
+    let expected_version = match precondition::<IfMatch>(req)? {
+        Some(IfMatch::Items(tags)) => match &current {
+            Some(c) if tags.iter().any(|t| t.strong_eq(&etag(c))) => Some(c.version),
+            _ => return Err(ApiError::PreconditionFailed),
+        },
+        Some(IfMatch::Any) | None => None,
+    };
+    let current = current.ok_or(ApiError::NotFound)?;
+    // Sending the current profile back is fine, changing it is for admins only
+    if input.profile.is_some_and(|p| p != current.profile) && !user.is_admin() {
+        return Err(ApiError::Forbidden);
+    }
+    let updated = users.update(id, input, user.id, expected_version).await?.ok_or(ApiError::NotFound)?;
+    Ok(HttpResponse::Ok().insert_header(ETag(etag(&updated))).json(UserView::from(updated)))
+}
+
+// Soft delete by default; `?purge=true` (admins only) removes the row for good. Either way the user's sessions end.
+pub async fn delete_user(users: web::Data<dyn UserRepository>, tokens: web::Data<dyn TokenStore>, cfg: web::Data<Settings>, denylist: web::Data<Denylist>, user: AuthUser, path: web::Path<i32>, query: web::Query<DeleteUserQuery>) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    if !user.can_manage(id) || (query.purge && !user.is_admin()) {
+        return Err(ApiError::Forbidden);
+    }
+    let found = if query.purge { users.purge(id).await? } else { users.delete(id, user.id).await? };
+    if !found {
+        return Err(ApiError::NotFound);
+    }
+    tokens.revoke_all_sessions(id).await?;
+    denylist.revoke_user(id, deny_until(&cfg));
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn restore_user(users: web::Data<dyn UserRepository>, user: AuthUser, path: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let restored = users.restore(path.into_inner(), user.id).await?.ok_or(ApiError::NotFound)?;
+    Ok(HttpResponse::Ok().json(UserView::from(restored)))
+}
+
+// Rows encoded into one response chunk, among those already waiting; a slow query still gets each row out as it comes
+const EXPORT_CHUNK_ROWS: usize = 256;
+const EXPORT_CSV_HEADER: &[u8] = b"id,username,email,profile\n";
+
+fn encode_rows(format: ExportFormat, rows: Vec<anyhow::Result<User>>) -> anyhow::Result<web::Bytes> {
+    let mut buf = Vec::new();
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(&mut buf);
+            for row in rows {
+                writer.serialize(UserView::from(row?))?;
             }
+            writer.flush()?;
+        }
+        ExportFormat::Ndjson => {
+            for row in rows {
+                serde_json::to_writer(&mut buf, &UserView::from(row?))?;
+                buf.push(b'\n');
+            }
+        }
+    }
+    Ok(buf.into())
+}
 
-            // Otherwise run with limited concurrency using buffer_unordered
-            let stream = stream::iter(futures_vec.into_iter().map(|fut| async { fut.await }));
-            let results: Vec<_> = stream.buffer_unordered(concurrency_limit).collect().await;
+// Admin only. Streams the users matching the GET /users filters (paging aside) straight from the database
+// cursor, so memory use does not depend on the table size. The status is sent before the first row, so an
+// error halfway through is logged and cuts the response short instead of becoming a problem+json.
+pub async fn export_users(users: web::Data<dyn UserRepository>, query: web::Query<UserListQuery>, export: web::Query<ExportQuery>) -> Result<HttpResponse, ApiError> {
+    let format = export.format;
+    let rows = users.export(user_query(&query, 0, i64::MAX)?).ready_chunks(EXPORT_CHUNK_ROWS).map(move |rows| {
+        encode_rows(format, rows).map_err(|e| {
+            eprintln!("export aborted: {:?}", e);
+            std::io::Error::other("export aborted")
+        })
+    });
+    let (content_type, filename) = match format {
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "users.csv"),
+        ExportFormat::Ndjson => ("application/x-ndjson", "users.ndjson"),
+    };
+    let header = match format {
+        ExportFormat::Csv => Some(Ok(web::Bytes::from_static(EXPORT_CSV_HEADER))),
+        ExportFormat::Ndjson => None,
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+        .streaming(stream::iter(header).chain(rows)))
+}
 
-            // flatten Option<User>
-            let mut out = Vec::new();
-            for r in results {
-                if let Ok(Some(u)) = r {
-                    out.push(u);
+// CSV with a header row, or a JSON array of the same objects
+fn parse_import(req: &HttpRequest, body: &[u8]) -> Result<Vec<ImportUser>, ApiError> {
+    match req.content_type() {
+        "text/csv" => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(body)
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(|e| ApiError::BadRequest(format!("invalid CSV: {}", e))),
+        "application/json" => serde_json::from_slice(body).map_err(|e| ApiError::BadRequest(format!("invalid JSON: {}", e))),
+        _ => Err(ApiError::BadRequest("expected a text/csv or application/json body".into())),
+    }
+}
+
+// Every row is checked before anything is written: the POST /users rules, duplicates inside the file and names
+// already taken. Only if all rows pass (and this is not a dry run) are they inserted, in a single transaction.
+pub async fn import_users(users: web::Data<dyn UserRepository>, cfg: web::Data<Settings>, user: AuthUser, req: HttpRequest, query: web::Query<ImportQuery>, body: web::Bytes) -> Result<HttpResponse, ApiError> {
+    let rows = parse_import(&req, &body)?;
+    if rows.is_empty() {
+        return Err(ApiError::BadRequest("nothing to import".into()));
+    }
+    if rows.len() > cfg.max_import_rows {
+        return Err(ApiError::BadRequest(format!("at most {} rows per import", cfg.max_import_rows)));
+    }
+
+    let mut report = Vec::with_capacity(rows.len());
+    let mut usernames = HashSet::new();
+    let mut emails = HashSet::new();
+    for (i, row) in rows.iter().enumerate() {
+        let mut errors = row.validate().err().map(|e| field_errors(&e)).unwrap_or_default();
+        let email = non_blank(row.email.as_deref());
+        if !usernames.insert(row.username.as_str()) {
+            errors.push(FieldError::new("username", "duplicate_in_import", "appears more than once in this import"));
+        }
+        if email.is_some_and(|e| !emails.insert(e)) {
+            errors.push(FieldError::new("email", "duplicate_in_import", "appears more than once in this import"));
+        }
+        if errors.is_empty()
+            && let Some(conflict) = users.find_conflict(Some(&row.username), email, None).await?
+        {
+            errors.push(FieldError::new(conflict.field(), conflict.code(), conflict.to_string()));
+        }
+        let status = if errors.is_empty() { ImportStatus::Valid } else { ImportStatus::Invalid };
+        report.push(ImportRowReport { row: i + 1, username: row.username.clone(), status, id: None, errors });
+    }
+    let invalid = report.iter().filter(|r| r.status == ImportStatus::Invalid).count();
+    if invalid > 0 || query.dry_run {
+        let status = if invalid > 0 { StatusCode::UNPROCESSABLE_ENTITY } else { StatusCode::OK };
+        return Ok(HttpResponse::build(status).json(ImportReport { dry_run: query.dry_run, total: rows.len(), invalid, created: 0, rows: report }));
+    }
+
+    let inputs = rows.into_iter().map(|row| row.into_create(cfg.default_profile_id)).collect();
+    let created = users.create_many(inputs, user.id).await?;
+    for (row, created) in report.iter_mut().zip(&created) {
+        row.status = ImportStatus::Created;
+        row.id = Some(created.id);
+    }
+    Ok(HttpResponse::Created().json(ImportReport { dry_run: false, total: report.len(), invalid: 0, created: created.len(), rows: report }))
+}
+
+// Example endpoint demonstrating concurrent data load using join_all (Promise.all equivalent).
+// Takes the page, filters and sort of GET /users, so one call loads at most MAX_PAGE_SIZE users.
+pub async fn load_concurrent(users: web::Data<dyn UserRepository>, cfg: web::Data<Settings>, query: web::Query<UserListQuery>) -> Result<HttpResponse, ApiError> {
+    // For demo, we will fetch one page of users and then fetch each user individually concurrently
+    let (page, page_size) = page_of(&query, &cfg);
+    let list = users.search(&user_query(&query, (page as i64 - 1) * page_size as i64, page_size as i64)?).await?.users;
+    let concurrency_limit = cfg.concurrency_limit;
+    let timeout_secs = cfg.db_query_timeout_secs;
+    let fail_fast = cfg.fail_fast;
+
+    // Build a vector of futures where each UserRepository::get is wrapped with a timeout
+    let futures_vec = list.into_iter().map(|u| {
+        let users = users.clone();
+        async move {
+            // apply per-query timeout
+            let fut = users.get(u.id);
+            match timeout(Duration::from_secs(timeout_secs), fut).await {
+                Ok(inner_res) => inner_res, // Result<Option<User>>
+                Err(_) => Err(anyhow::anyhow!("timeout")),
+            }
+        }
+    }).collect::<Vec<_>>();
+
+    // If fail_fast is desired, use try_join_all which returns Err on first Err.
+    if fail_fast {
+        match try_join_all(futures_vec).await {
+            Ok(results) => {
+                let mut out = Vec::new();
+                for u in results.into_iter().flatten() {
+                    out.push(UserView::from(u));
                 }
+                return Ok(HttpResponse::Ok().json(out));
             }
-            HttpResponse::Ok().json(out)
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // Otherwise run with limited concurrency using buffer_unordered
+    let stream = stream::iter(futures_vec);
+    let results: Vec<_> = stream.buffer_unordered(concurrency_limit).collect().await;
+
+    // flatten Option<User>
+    let mut out = Vec::new();
+    for r in results {
+        if let Ok(Some(u)) = r {
+            out.push(UserView::from(u));
         }
-        Err(_) => HttpResponse::InternalServerError().finish(),
     }
+    Ok(HttpResponse::Ok().json(out))
 }
@@ -1,41 +1,72 @@
 mod config;
 mod models;
 mod db;
+mod error;
 mod auth;
 mod token;
+mod refresh;
 mod handlers;
+mod middleware;
+mod migrations;
+mod repository;
+mod routes;
+#[cfg(test)]
+mod api_tests;
 
 use actix_web::{web, App, HttpServer};
+use config::StorageBackend;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let settings = config::Settings::from_env();
-    println!("Using DB host: {:?}", settings.db.host);
+    if let Some(url) = &settings.db.url && settings.db.backend != StorageBackend::Memory && StorageBackend::from_url(url).is_none() {
+        eprintln!("Unsupported DATABASE_URL scheme, expected sqlite:, postgres: or mssql:");
+        std::process::exit(1);
+    }
 
-    let pool = match db::init_db(&settings).await {
-        Ok(p) => p,
+    match settings.db.backend {
+        StorageBackend::Memory => println!("Using in-memory storage"),
+        StorageBackend::Sqlite => println!("Using SQLite database"),
+        StorageBackend::Postgres => println!("Using PostgreSQL database"),
+        StorageBackend::Mssql => println!("Using DB host: {:?}", settings.db.host),
+    }
+    let database = match db::connect(&settings).await {
+        Ok(d) => d,
         Err(e) => {
             eprintln!("Failed to init db: {}", e);
             std::process::exit(1);
         }
     };
 
-    let data_pool = web::Data::new(pool);
+    // `backend migrate up|down|status` runs the embedded migrations and exits
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        if let Err(e) = migrations::command(&database, args.get(2).map(String::as_str)).await {
+            eprintln!("migrate: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Err(e) = migrations::check_on_start(&database, settings.migrate_on_start).await {
+        eprintln!("Failed to check schema: {}", e);
+        std::process::exit(1);
+    }
+
+    let (users, tokens) = database.repositories();
+    let data_users = web::Data::from(users);
+    let data_tokens = web::Data::from(tokens);
     let data_cfg = web::Data::new(settings.clone());
+    let data_denylist = web::Data::new(auth::Denylist::default());
 
     let bind_addr = format!("0.0.0.0:{}", settings.port);
 
     HttpServer::new(move || {
         App::new()
-            .app_data(data_pool.clone())
+            .app_data(data_users.clone())
+            .app_data(data_tokens.clone())
             .app_data(data_cfg.clone())
-            .route("/login", web::post().to(handlers::login))
-            .route("/users", web::post().to(handlers::create_user))
-            .route("/users", web::get().to(handlers::list_users))
-            .route("/users/{id}", web::get().to(handlers::get_user))
-            .route("/users/{id}", web::put().to(handlers::update_user))
-            .route("/users/{id}", web::delete().to(handlers::delete_user))
-            .route("/load_concurrent", web::get().to(handlers::load_concurrent))
+            .app_data(data_denylist.clone())
+            .configure(routes::configure)
     })
     .bind(&bind_addr)?
     .run()
@@ -0,0 +1,114 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use futures::future::LocalBoxFuture;
+use crate::auth::{self, AuthUser, Denylist};
+use crate::config::{Settings, TokenMode};
+use crate::error::ApiError;
+use crate::repository::{TokenStore, UserRepository};
+use crate::token::TokenService;
+
+// Validates the bearer token and stores the AuthUser in request extensions.
+// Opaque tokens are checked against the session store; JWTs are verified in-process plus the denylist.
+pub async fn require_auth(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    match authenticate(&req).await? {
+        Some(user) => {
+            req.extensions_mut().insert(user);
+            next.call(req).await
+        }
+        None => Err(ApiError::Unauthorized.into()),
+    }
+}
+
+// For public routes that behave differently for a signed-in caller: a valid bearer token stores the AuthUser
+// like require_auth does, while a missing or invalid one lets the request through anonymously
+pub async fn optional_auth(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if let Some(user) = authenticate(&req).await? {
+        req.extensions_mut().insert(user);
+    }
+    next.call(req).await
+}
+
+// None when there is no bearer token or it does not resolve to a live session
+async fn authenticate(req: &ServiceRequest) -> Result<Option<AuthUser>, Error> {
+    let Some(token) = TokenService::extract_token_from_header(req.request()) else { return Ok(None) };
+    let cfg = match req.app_data::<web::Data<Settings>>() {
+        Some(c) => c.clone(),
+        None => return Err(ApiError::Internal(anyhow::anyhow!("Settings missing from app data")).into()),
+    };
+    match cfg.token_mode {
+        TokenMode::Jwt => {
+            let denylist = match req.app_data::<web::Data<Denylist>>() {
+                Some(d) => d.clone(),
+                None => return Err(ApiError::Internal(anyhow::anyhow!("Denylist missing from app data")).into()),
+            };
+            match auth::decode_token(&token, &cfg) {
+                Ok(claims) if !denylist.is_revoked(&claims) => Ok(Some(AuthUser::from(&claims))),
+                _ => Ok(None),
+            }
+        }
+        TokenMode::Opaque => {
+            let (tokens, users) = match (req.app_data::<web::Data<dyn TokenStore>>(), req.app_data::<web::Data<dyn UserRepository>>()) {
+                (Some(t), Some(u)) => (t.clone(), u.clone()),
+                _ => return Err(ApiError::Internal(anyhow::anyhow!("TokenStore or UserRepository missing from app data")).into()),
+            };
+            TokenService::authenticate(tokens.get_ref(), users.get_ref(), &cfg, &token).await.map_err(|e| ApiError::from(e).into())
+        }
+    }
+}
+
+// Route guard for use after require_auth, e.g. `.wrap(from_fn(require_role("admin"))).wrap(from_fn(require_auth))`
+pub fn require_role(role: &'static str) -> impl Fn(ServiceRequest, Next<BoxBody>) -> LocalBoxFuture<'static, Result<ServiceResponse<BoxBody>, Error>> {
+    move |req, next| {
+        Box::pin(async move {
+            let allowed = req.extensions().get::<AuthUser>().is_some_and(|u| u.role == role);
+            if !allowed {
+                return Err(ApiError::Forbidden.into());
+            }
+            next.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App, HttpResponse};
+
+    // Stands in for require_auth: the role comes from a test header
+    async fn fake_auth(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+        if let Some(role) = req.headers().get("x-test-role").and_then(|v| v.to_str().ok()).map(str::to_string) {
+            req.extensions_mut().insert(AuthUser { id: 1, username: "ana".into(), session_id: 1, role });
+        }
+        next.call(req).await
+    }
+
+    async fn status_for(role: Option<&str>) -> u16 {
+        let app = test::init_service(App::new().route(
+            "/admin",
+            web::get().to(|| async { HttpResponse::Ok().finish() }).wrap(from_fn(require_role(auth::ROLE_ADMIN))).wrap(from_fn(fake_auth)),
+        ))
+        .await;
+        let mut req = test::TestRequest::get().uri("/admin");
+        if let Some(role) = role {
+            req = req.insert_header(("x-test-role", role));
+        }
+        match test::try_call_service(&app, req.to_request()).await {
+            Ok(res) => res.status().as_u16(),
+            Err(e) => e.as_response_error().status_code().as_u16(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn require_role_allows_matching_role() {
+        assert_eq!(status_for(Some(auth::ROLE_ADMIN)).await, 200);
+    }
+
+    #[actix_web::test]
+    async fn require_role_rejects_other_roles() {
+        assert_eq!(status_for(Some(auth::ROLE_USER)).await, 403);
+        assert_eq!(status_for(None).await, 403);
+    }
+}
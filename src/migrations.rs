@@ -0,0 +1,382 @@
+use crate::config::MigrateOnStart;
+use crate::db::{self, Database};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use sqlx::{Executor, Mssql, Pool, Postgres, Sqlite};
+
+// Schema changes live in migrations/<backend>/NNNN_name.{up,down}.sql and are compiled into the binary.
+// Applied versions are recorded in schema_migrations; each migration runs in its own transaction.
+// Versions are numbered across backends, so a backend skips the versions it has nothing to do for
+// (SQLite and PostgreSQL have no procedures for 0002/0003/0009 to change, and their 0001 always creates the
+// unique username constraint that 0008 adds to adopted SQL Server tables).
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+macro_rules! migration {
+    ($backend:literal, $version:literal, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $backend, "/", $name, ".up.sql")),
+            down: include_str!(concat!("../migrations/", $backend, "/", $name, ".down.sql")),
+        }
+    };
+}
+
+const MSSQL: &[Migration] = &[
+    migration!("mssql", 1, "0001_initial"),
+    migration!("mssql", 2, "0002_procedure_rowcount"),
+    migration!("mssql", 3, "0003_insert_returns_id"),
+    migration!("mssql", 4, "0004_unique_email"),
+    migration!("mssql", 5, "0005_soft_delete"),
+    migration!("mssql", 6, "0006_row_version"),
+    migration!("mssql", 7, "0007_null_blank_emails"),
+    migration!("mssql", 8, "0008_unique_username"),
+    migration!("mssql", 9, "0009_update_keeps_creation_audit"),
+];
+const SQLITE: &[Migration] = &[
+    migration!("sqlite", 1, "0001_initial"),
+    migration!("sqlite", 4, "0004_unique_email"),
+    migration!("sqlite", 5, "0005_soft_delete"),
+    migration!("sqlite", 6, "0006_row_version"),
+    migration!("sqlite", 7, "0007_null_blank_emails"),
+];
+const POSTGRES: &[Migration] = &[
+    migration!("postgres", 1, "0001_initial"),
+    migration!("postgres", 4, "0004_unique_email"),
+    migration!("postgres", 5, "0005_soft_delete"),
+    migration!("postgres", 6, "0006_row_version"),
+    migration!("postgres", 7, "0007_null_blank_emails"),
+];
+
+// T-SQL procedures must start their own batch, so scripts are split on GO lines the way sqlcmd does
+fn batches(script: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    for line in script.lines() {
+        if line.trim().eq_ignore_ascii_case("go") {
+            out.push(std::mem::take(&mut current));
+        } else {
+            current.push_str(line);
+            current.push('\n');
+        }
+    }
+    out.push(current);
+    out.iter().map(|b| b.trim()).filter(|b| !b.is_empty()).map(str::to_string).collect()
+}
+
+#[async_trait]
+trait MigrationTarget: Sync {
+    async fn ensure_table(&self) -> Result<()>;
+    async fn applied(&self) -> Result<Vec<i64>>;
+    // Run the up / down script and record / forget the version in the same transaction
+    async fn apply(&self, m: &Migration) -> Result<()>;
+    async fn revert(&self, m: &Migration) -> Result<()>;
+}
+
+#[async_trait]
+impl MigrationTarget for Pool<Mssql> {
+    async fn ensure_table(&self) -> Result<()> {
+        self.execute("IF OBJECT_ID(N'dbo.schema_migrations', N'U') IS NULL CREATE TABLE dbo.schema_migrations (version BIGINT NOT NULL PRIMARY KEY, name NVARCHAR(200) NOT NULL, applied_at DATETIME NOT NULL)").await?;
+        Ok(())
+    }
+
+    async fn applied(&self) -> Result<Vec<i64>> {
+        let rows = sqlx::query_as::<_, (i64,)>("SELECT version FROM schema_migrations ORDER BY version").fetch_all(self).await?;
+        Ok(rows.into_iter().map(|(v,)| v).collect())
+    }
+
+    async fn apply(&self, m: &Migration) -> Result<()> {
+        let mut tx = self.begin().await?;
+        for batch in batches(m.up) {
+            tx.execute(batch.as_str()).await?;
+        }
+        sqlx::query("INSERT INTO schema_migrations (version, name, applied_at) VALUES (@p1, @p2, @p3)")
+            .bind(m.version)
+            .bind(m.name)
+            .bind(db::utc_now())
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn revert(&self, m: &Migration) -> Result<()> {
+        let mut tx = self.begin().await?;
+        for batch in batches(m.down) {
+            tx.execute(batch.as_str()).await?;
+        }
+        sqlx::query("DELETE FROM schema_migrations WHERE version = @p1").bind(m.version).execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MigrationTarget for Pool<Sqlite> {
+    async fn ensure_table(&self) -> Result<()> {
+        self.execute("CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER PRIMARY KEY, name TEXT NOT NULL, applied_at TEXT NOT NULL)").await?;
+        Ok(())
+    }
+
+    async fn applied(&self) -> Result<Vec<i64>> {
+        let rows = sqlx::query_as::<_, (i64,)>("SELECT version FROM schema_migrations ORDER BY version").fetch_all(self).await?;
+        Ok(rows.into_iter().map(|(v,)| v).collect())
+    }
+
+    async fn apply(&self, m: &Migration) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.execute(m.up).await?;
+        sqlx::query("INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)")
+            .bind(m.version)
+            .bind(m.name)
+            .bind(db::utc_now())
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn revert(&self, m: &Migration) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.execute(m.down).await?;
+        sqlx::query("DELETE FROM schema_migrations WHERE version = ?1").bind(m.version).execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MigrationTarget for Pool<Postgres> {
+    async fn ensure_table(&self) -> Result<()> {
+        self.execute("CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT PRIMARY KEY, name VARCHAR(200) NOT NULL, applied_at TIMESTAMP NOT NULL)").await?;
+        Ok(())
+    }
+
+    async fn applied(&self) -> Result<Vec<i64>> {
+        let rows = sqlx::query_as::<_, (i64,)>("SELECT version FROM schema_migrations ORDER BY version").fetch_all(self).await?;
+        Ok(rows.into_iter().map(|(v,)| v).collect())
+    }
+
+    async fn apply(&self, m: &Migration) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.execute(m.up).await?;
+        sqlx::query("INSERT INTO schema_migrations (version, name, applied_at) VALUES ($1, $2, $3::timestamp)")
+            .bind(m.version)
+            .bind(m.name)
+            .bind(db::utc_now())
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn revert(&self, m: &Migration) -> Result<()> {
+        let mut tx = self.begin().await?;
+        tx.execute(m.down).await?;
+        sqlx::query("DELETE FROM schema_migrations WHERE version = $1").bind(m.version).execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+// None for the in-memory backend, which has no schema
+fn target(database: &Database) -> Option<(&dyn MigrationTarget, &'static [Migration])> {
+    match database {
+        Database::Memory => None,
+        Database::Mssql(pool) => Some((pool, MSSQL)),
+        Database::Sqlite(pool) => Some((pool, SQLITE)),
+        Database::Postgres(pool) => Some((pool, POSTGRES)),
+    }
+}
+
+// Every embedded migration paired with whether it has been applied
+pub async fn status(database: &Database) -> Result<Vec<(&'static Migration, bool)>> {
+    let Some((target, migrations)) = target(database) else { return Ok(Vec::new()) };
+    target.ensure_table().await?;
+    let applied = target.applied().await?;
+    Ok(migrations.iter().map(|m| (m, applied.contains(&m.version))).collect())
+}
+
+pub async fn pending(database: &Database) -> Result<Vec<&'static Migration>> {
+    Ok(status(database).await?.into_iter().filter(|(_, applied)| !applied).map(|(m, _)| m).collect())
+}
+
+// Applies every pending migration in version order and returns them
+pub async fn up(database: &Database) -> Result<Vec<&'static Migration>> {
+    let Some((target, _)) = target(database) else { return Ok(Vec::new()) };
+    let pending = pending(database).await?;
+    for m in &pending {
+        target.apply(m).await?;
+    }
+    Ok(pending)
+}
+
+// Reverts the most recently applied migration, if any
+pub async fn down(database: &Database) -> Result<Option<&'static Migration>> {
+    let Some((target, migrations)) = target(database) else { return Ok(None) };
+    target.ensure_table().await?;
+    // Older databases may have recorded the skipped versions back when they were no-op scripts; those are
+    // left alone. A version above the newest one embedded comes from a newer binary.
+    let newest = migrations.last().map_or(0, |m| m.version);
+    let known = |v: &i64| *v > newest || migrations.iter().any(|m| m.version == *v);
+    let Some(latest) = target.applied().await?.into_iter().filter(known).max() else { return Ok(None) };
+    let Some(m) = migrations.iter().find(|m| m.version == latest) else {
+        bail!("latest applied migration {} is not known to this binary", latest);
+    };
+    target.revert(m).await?;
+    Ok(Some(m))
+}
+
+// `backend migrate up|down|status`
+pub async fn command(database: &Database, action: Option<&str>) -> Result<()> {
+    if matches!(database, Database::Memory) {
+        bail!("the in-memory backend has no schema to migrate");
+    }
+    match action {
+        Some("up") => {
+            let applied = up(database).await?;
+            if applied.is_empty() {
+                println!("Schema is up to date");
+            }
+            for m in applied {
+                println!("Applied {}", m.name);
+            }
+        }
+        Some("down") => match down(database).await? {
+            Some(m) => println!("Reverted {}", m.name),
+            None => println!("No migrations applied"),
+        },
+        Some("status") => {
+            for (m, applied) in status(database).await? {
+                println!("{} {}", if applied { "applied" } else { "pending" }, m.name);
+            }
+        }
+        _ => bail!("usage: backend migrate up|down|status"),
+    }
+    Ok(())
+}
+
+pub async fn check_on_start(database: &Database, policy: MigrateOnStart) -> Result<()> {
+    let pending = pending(database).await?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let names = pending.iter().map(|m| m.name).collect::<Vec<_>>().join(", ");
+    match policy {
+        MigrateOnStart::Apply => {
+            for m in up(database).await? {
+                println!("Applied migration {}", m.name);
+            }
+            Ok(())
+        }
+        MigrateOnStart::Strict => bail!("schema is behind, pending migrations: {} (run `backend migrate up`)", names),
+        MigrateOnStart::Warn => {
+            eprintln!("warning: schema is behind, pending migrations: {}", names);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn sqlite() -> Database {
+        Database::Sqlite(sqlx::sqlite::SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap())
+    }
+
+    async fn tables(database: &Database) -> Vec<String> {
+        let Database::Sqlite(pool) = database else { unreachable!() };
+        sqlx::query_as::<_, (String,)>("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(n,)| n)
+            .collect()
+    }
+
+    #[test]
+    fn versions_are_ordered_and_named_after_their_number() {
+        for ms in [MSSQL, SQLITE, POSTGRES] {
+            assert!(ms.windows(2).all(|w| w[0].version < w[1].version));
+            assert!(ms.iter().all(|m| m.name.starts_with(&format!("{:04}_", m.version))));
+            assert!(ms.iter().all(|m| !m.up.trim().is_empty() && !m.down.trim().is_empty()));
+        }
+    }
+
+    // Adopting an existing SQL Server database must keep its procedures; only later, revertible steps change them
+    #[test]
+    fn mssql_baseline_never_replaces_procedures() {
+        assert!(!MSSQL[0].up.contains("CREATE OR ALTER"));
+    }
+
+    // A usuarios table adopted by 0001 keeps whatever constraints it had, so a later step must make usernames unique
+    #[test]
+    fn mssql_usernames_are_unique_on_adopted_tables() {
+        let m = MSSQL.iter().find(|m| m.name == "0008_unique_username").unwrap();
+        assert!(m.up.contains("IS NULL") && m.up.contains("CREATE UNIQUE INDEX UX_usuarios_nombre_usr ON dbo.usuarios (nombre_usr)"));
+        assert!(m.down.contains("DROP INDEX UX_usuarios_nombre_usr"));
+    }
+
+    // An update must not rewrite who created the user or when
+    #[test]
+    fn mssql_update_leaves_creation_audit_alone() {
+        let m = MSSQL.iter().find(|m| m.name == "0009_update_keeps_creation_audit").unwrap();
+        assert!(m.up.contains("PROCEDURE dbo.sp_usuarios_update"));
+        assert!(!m.up.contains("@usercrea") && !m.up.contains("@fechcrea"));
+        assert!(!m.up.contains("usercrea =") && !m.up.contains("fechcrea ="));
+    }
+
+    #[test]
+    fn scripts_are_split_on_go_lines() {
+        let script = "CREATE TABLE a (x INT);\nGO\n\nCREATE OR ALTER PROCEDURE p AS SELECT 1;\n  go  \nSELECT 'GO';\n";
+        let parts = batches(script);
+        assert_eq!(parts.len(), 3);
+        assert!(parts[1].starts_with("CREATE OR ALTER PROCEDURE"));
+        assert_eq!(parts[2], "SELECT 'GO';");
+    }
+
+    #[actix_web::test]
+    async fn up_down_and_status_track_applied_versions() {
+        let database = sqlite().await;
+        assert_eq!(pending(&database).await.unwrap().len(), SQLITE.len());
+
+        let applied = up(&database).await.unwrap();
+        assert_eq!(applied.len(), SQLITE.len());
+        assert!(pending(&database).await.unwrap().is_empty());
+        assert!(up(&database).await.unwrap().is_empty());
+        assert_eq!(tables(&database).await, ["refreshtoken", "schema_migrations", "usertoken", "usuarios"]);
+        assert!(check_on_start(&database, MigrateOnStart::Strict).await.is_ok());
+
+        let reverted = down(&database).await.unwrap().unwrap();
+        assert_eq!(reverted.version, SQLITE.last().unwrap().version);
+        assert_eq!(pending(&database).await.unwrap().len(), 1);
+        assert!(check_on_start(&database, MigrateOnStart::Strict).await.is_err());
+        assert!(check_on_start(&database, MigrateOnStart::Warn).await.is_ok());
+
+        check_on_start(&database, MigrateOnStart::Apply).await.unwrap();
+        assert!(pending(&database).await.unwrap().is_empty());
+    }
+    #[actix_web::test]
+    async fn down_skips_versions_this_backend_no_longer_has() {
+        let database = sqlite().await;
+        up(&database).await.unwrap();
+        let Database::Sqlite(pool) = &database else { unreachable!() };
+        for version in [2, 3] {
+            sqlx::query("INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, 'retired', '2020-01-01 00:00:00')").bind(version).execute(pool).await.unwrap();
+        }
+        assert!(pending(&database).await.unwrap().is_empty());
+        let mut reverted = Vec::new();
+        while let Some(m) = down(&database).await.unwrap() {
+            reverted.push(m.version);
+        }
+        assert_eq!(reverted, SQLITE.iter().rev().map(|m| m.version).collect::<Vec<_>>());
+        assert_eq!(tables(&database).await, ["schema_migrations"]);
+    }
+}
@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use crate::error::FieldError;
+use validator::{Validate, ValidationError};
 
-#[derive(Serialize, Deserialize, FromRow, Debug, Clone)]
+// Internal row type: carries the bcrypt hash and is intentionally not Serialize; responses use UserView
+#[derive(FromRow, Debug, Clone)]
 pub struct User {
     #[sqlx(rename = "codusr_usr")]
     pub id: i32,
@@ -11,29 +14,344 @@ pub struct User {
     pub email: Option<String>,
     #[sqlx(rename = "contrasena_usr")]
     pub password_hash: String,
+    #[sqlx(rename = "codperf_usr")]
+    pub profile: i32,
+    // Bumped by every write; exposed as the ETag of the user
+    #[sqlx(rename = "row_version")]
+    pub version: i32,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserView {
+    pub id: i32,
+    pub username: String,
+    pub email: Option<String>,
+    pub profile: i32,
+}
+
+impl From<User> for UserView {
+    fn from(u: User) -> Self {
+        UserView { id: u.id, username: u.username, email: u.email, profile: u.profile }
+    }
+}
+
+// At least one letter and one digit. bcrypt ignores everything past 72 bytes, hence the upper bound.
+fn password_policy(password: &str) -> Result<(), ValidationError> {
+    if password.chars().any(char::is_alphabetic) && password.chars().any(|c| c.is_ascii_digit()) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("password_policy").with_message("must contain at least one letter and one digit".into()))
+    }
+}
+
+// Length limits follow the usuarios columns: nombre_usr NVARCHAR(100), email_usr NVARCHAR(150)
+#[derive(Serialize, Deserialize, Validate)]
 pub struct CreateUser {
+    #[validate(length(min = 3, max = 100, message = "must be between 3 and 100 characters"))]
     pub username: String,
+    #[validate(email(message = "must be a valid email address"), length(max = 150, message = "must be at most 150 characters"))]
     pub email: Option<String>,
+    #[validate(length(min = 8, max = 72, message = "must be between 8 and 72 characters"), custom(function = "password_policy"))]
     pub password: String,
 }
 
-#[derive(Serialize, Deserialize)]
+// Deserializes a field that is present in the body; paired with `default` so an absent field stays None.
+// Only email is nullable, so `null` on any other field is rejected instead of being read as "unchanged".
+fn present<'de, D: serde::Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+// PATCH /users/{id} as a JSON merge patch (RFC 7386): an absent field is left unchanged and
+// `"email": null` clears the email. Also what the repositories take for every update.
+#[derive(Serialize, Deserialize, Validate, Default)]
 pub struct UpdateUser {
+    #[serde(default, deserialize_with = "present")]
+    #[validate(length(min = 3, max = 100, message = "must be between 3 and 100 characters"))]
     pub username: Option<String>,
+    // None: unchanged, Some(None): cleared
+    #[serde(default, deserialize_with = "present")]
+    #[validate(email(message = "must be a valid email address"), length(max = 150, message = "must be at most 150 characters"))]
+    pub email: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    #[validate(length(min = 8, max = 72, message = "must be between 8 and 72 characters"), custom(function = "password_policy"))]
+    pub password: Option<String>,
+    // codperf_usr; only admins may change it
+    #[serde(default, deserialize_with = "present")]
+    #[validate(range(min = 1, message = "must be a positive profile id"))]
+    pub profile: Option<i32>,
+}
+
+// PUT /users/{id}: the whole representation as GET returns it, so a missing email clears it. The password
+// is write-only and therefore optional; without it the current one is kept.
+#[derive(Serialize, Deserialize, Validate)]
+pub struct ReplaceUser {
+    #[validate(length(min = 3, max = 100, message = "must be between 3 and 100 characters"))]
+    pub username: String,
+    #[validate(email(message = "must be a valid email address"), length(max = 150, message = "must be at most 150 characters"))]
     pub email: Option<String>,
+    #[validate(length(min = 8, max = 72, message = "must be between 8 and 72 characters"), custom(function = "password_policy"))]
     pub password: Option<String>,
+    // codperf_usr; non-admins must send their current one
+    #[validate(range(min = 1, message = "must be a positive profile id"))]
+    pub profile: i32,
 }
 
-#[derive(Serialize, Deserialize)]
+impl From<ReplaceUser> for UpdateUser {
+    fn from(r: ReplaceUser) -> Self {
+        UpdateUser { username: Some(r.username), email: Some(r.email), password: r.password, profile: Some(r.profile) }
+    }
+}
+
+// One row of POST /users/import: an element of the JSON array or a CSV record with these column names.
+// Same rules as POST /users; without a profile the user gets DEFAULT_PROFILE_ID.
+#[derive(Serialize, Deserialize, Validate)]
+pub struct ImportUser {
+    #[validate(length(min = 3, max = 100, message = "must be between 3 and 100 characters"))]
+    pub username: String,
+    #[validate(email(message = "must be a valid email address"), length(max = 150, message = "must be at most 150 characters"))]
+    pub email: Option<String>,
+    #[validate(length(min = 8, max = 72, message = "must be between 8 and 72 characters"), custom(function = "password_policy"))]
+    pub password: String,
+    #[validate(range(min = 1, message = "must be a positive profile id"))]
+    pub profile: Option<i32>,
+}
+
+impl ImportUser {
+    pub fn into_create(self, default_profile: i32) -> (CreateUser, i32) {
+        let profile = self.profile.unwrap_or(default_profile);
+        (CreateUser { username: self.username, email: self.email, password: self.password }, profile)
+    }
+}
+
+// POST /users/import
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    // Validate every row and report, without writing anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    // Passed every check; only reported by dry runs and by imports rejected because of other rows
+    Valid,
+    Invalid,
+    Created,
+}
+
+#[derive(Serialize)]
+pub struct ImportRowReport {
+    // 1-based, not counting the CSV header
+    pub row: usize,
+    pub username: String,
+    pub status: ImportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub total: usize,
+    pub invalid: usize,
+    pub created: usize,
+    pub rows: Vec<ImportRowReport>,
+}
+
+// GET /users. Also serialized back into the next/prev links, so it round-trips through serde_urlencoded.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct UserListQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<String>,
+}
+
+// GET /users/export; the filters and sort are the UserListQuery ones
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    // One UserView JSON object per line
+    Ndjson,
+}
+
+#[derive(Serialize)]
+pub struct PageLinks {
+    #[serde(rename = "self")]
+    pub current: String,
+    pub next: Option<String>,
+    pub prev: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UserListPage {
+    pub items: Vec<UserView>,
+    pub page: u32,
+    pub page_size: u32,
+    pub total: i64,
+    pub total_pages: i64,
+    pub links: PageLinks,
+}
+
+// DELETE /users/{id}
+#[derive(Deserialize)]
+pub struct DeleteUserQuery {
+    #[serde(default)]
+    pub purge: bool,
+}
+
+// GET /users/availability; at least one of the two is required
+#[derive(Deserialize)]
+pub struct AvailabilityQuery {
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+// true when the value is free; fields that were not asked about are left out
+#[derive(Serialize)]
+pub struct Availability {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<bool>,
+}
+
+// Only shape checks: the password policy is not applied here, so accounts created before it can still log in
+#[derive(Serialize, Deserialize, Validate)]
 pub struct LoginRequest {
+    // Username or email
+    #[validate(length(min = 1, max = 150, message = "must be between 1 and 150 characters"))]
     pub username: String,
+    #[validate(length(min = 1, max = 72, message = "must be between 1 and 72 characters"))]
     pub password: String,
+    // usertoken.DeviceName NVARCHAR(100)
+    #[validate(length(max = 100, message = "must be at most 100 characters"))]
+    #[serde(default)]
+    pub device_name: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginResponse {
     pub token: String,
+    pub refresh_token: String,
+}
+
+#[derive(Serialize, FromRow, Debug, Clone)]
+pub struct SessionView {
+    #[sqlx(rename = "TokenID")]
+    pub id: i32,
+    #[sqlx(rename = "DeviceName")]
+    pub device_name: Option<String>,
+    #[sqlx(rename = "UserAgent")]
+    pub user_agent: Option<String>,
+    #[sqlx(rename = "IpAddress")]
+    pub ip_address: Option<String>,
+    #[sqlx(rename = "createdDate")]
+    pub created_at: String,
+    #[sqlx(rename = "LastSeen")]
+    pub last_seen: Option<String>,
+    // true for the session the request was made with
+    #[sqlx(default)]
+    pub current: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_user(id: i32) -> User {
+        User {
+            id,
+            username: format!("user{}", id),
+            email: Some(format!("user{}@example.com", id)),
+            password_hash: "$2b$12$abcdefghijklmnopqrstuuJ9dQbX8ZkKxk0r8T4CzqB1o4a3lOZ2u".into(),
+            profile: 1,
+            version: 1,
+        }
+    }
+
+    fn assert_no_hash(body: &str, user: &User) {
+        assert!(!body.contains("password_hash"), "{}", body);
+        assert!(!body.contains("contrasena"), "{}", body);
+        assert!(!body.contains(&user.password_hash), "{}", body);
+    }
+
+    #[test]
+    fn user_view_omits_hash() {
+        let user = sample_user(1);
+        let body = serde_json::to_string(&UserView::from(user.clone())).unwrap();
+        assert_no_hash(&body, &user);
+        assert!(body.contains("\"username\":\"user1\""));
+    }
+
+    #[test]
+    fn create_user_reports_every_invalid_field() {
+        let input = CreateUser { username: "".into(), email: Some("not-an-email".into()), password: "short".into() };
+        let errors = input.validate().unwrap_err();
+        let mut fields: Vec<String> = errors.field_errors().keys().map(|k| k.to_string()).collect();
+        fields.sort();
+        assert_eq!(fields, ["email", "password", "username"]);
+    }
+
+    #[test]
+    fn password_policy_needs_letters_and_digits() {
+        let with = |password: &str| CreateUser { username: "ana".into(), email: None, password: password.into() }.validate();
+        assert!(with("abcdefgh").is_err());
+        assert!(with("12345678").is_err());
+        assert!(with("abcd1234").is_ok());
+        assert!(with(&format!("a1{}", "x".repeat(71))).is_err());
+    }
+
+    #[test]
+    fn update_user_only_checks_present_fields() {
+        let input = UpdateUser { email: Some(Some("ana@example.com".into())), ..Default::default() };
+        assert!(input.validate().is_ok());
+        let input = UpdateUser { email: Some(None), ..Default::default() };
+        assert!(input.validate().is_ok());
+        let input = UpdateUser { username: Some("a".repeat(101)), profile: Some(0), ..Default::default() };
+        assert_eq!(input.validate().unwrap_err().field_errors().len(), 2);
+    }
+
+    #[test]
+    fn merge_patch_tells_absent_from_null() {
+        let patch: UpdateUser = serde_json::from_str(r#"{ "email": null }"#).unwrap();
+        assert_eq!((patch.username, patch.email), (None, Some(None)));
+        let patch: UpdateUser = serde_json::from_str(r#"{ "username": "ana" }"#).unwrap();
+        assert_eq!((patch.username.as_deref(), patch.email), (Some("ana"), None));
+        assert!(serde_json::from_str::<UpdateUser>(r#"{ "username": null }"#).is_err());
+    }
+
+    #[test]
+    fn user_view_list_omits_hash() {
+        let users: Vec<User> = (1..=3).map(sample_user).collect();
+        let views: Vec<UserView> = users.iter().cloned().map(UserView::from).collect();
+        let body = serde_json::to_string(&views).unwrap();
+        for user in &users {
+            assert_no_hash(&body, user);
+        }
+    }
 }
@@ -0,0 +1,102 @@
+use anyhow::Result;
+use crate::db;
+use crate::repository::TokenStore;
+use crate::token::{generate_opaque_token, hash_token, hashes_match};
+
+// Refresh tokens are stored one row per issued token. Every token minted from the same login
+// shares a FamilyID (also stored on that login's session); rotating marks the old row as used,
+// and presenting a used token revokes the family.
+pub enum RefreshOutcome {
+    Rotated { user_id: i32, family_id: String, refresh_token: String },
+    // Unknown, expired or revoked token
+    Invalid,
+    // A token that was already rotated came back: the family has been revoked
+    Reused,
+}
+
+pub struct RefreshTokenService;
+
+impl RefreshTokenService {
+    pub async fn issue(tokens: &dyn TokenStore, user_id: i32, family_id: &str, ttl_days: i64) -> Result<String> {
+        let token = generate_opaque_token();
+        let expires_at = db::utc_timestamp(chrono::Utc::now() + chrono::Duration::days(ttl_days));
+        tokens.insert_refresh(user_id, family_id, &hash_token(&token), &expires_at).await?;
+        Ok(token)
+    }
+
+    pub async fn rotate(tokens: &dyn TokenStore, raw_token: &str, ttl_days: i64) -> Result<RefreshOutcome> {
+        let token_hash = hash_token(raw_token);
+        let row = match tokens.find_refresh(&token_hash).await? {
+            Some(r) if hashes_match(&r.token_hash, &token_hash) => r,
+            _ => return Ok(RefreshOutcome::Invalid),
+        };
+        if row.revoked {
+            return Ok(RefreshOutcome::Invalid);
+        }
+        if row.used {
+            tokens.revoke_refresh_family(&row.family_id).await?;
+            return Ok(RefreshOutcome::Reused);
+        }
+        if row.expires_at <= db::utc_now() {
+            return Ok(RefreshOutcome::Invalid);
+        }
+        // Only one concurrent rotation can flip `used`; the loser is treated as a replay
+        if !tokens.mark_refresh_used(row.id, row.user_id).await? {
+            tokens.revoke_refresh_family(&row.family_id).await?;
+            return Ok(RefreshOutcome::Reused);
+        }
+        let refresh_token = Self::issue(tokens, row.user_id, &row.family_id, ttl_days).await?;
+        Ok(RefreshOutcome::Rotated { user_id: row.user_id, family_id: row.family_id, refresh_token })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::db::Database;
+    use crate::migrations;
+    use crate::repository::{sqlite, MemoryTokenStore, SqliteTokenStore};
+
+    async fn stores() -> Vec<Box<dyn TokenStore>> {
+        let pool = sqlite::connect(&Settings::for_tests(), "sqlite::memory:").await.unwrap();
+        migrations::up(&Database::Sqlite(pool.clone())).await.unwrap();
+        vec![Box::new(MemoryTokenStore::default()), Box::new(SqliteTokenStore::new(pool))]
+    }
+
+    fn rotated(outcome: RefreshOutcome) -> String {
+        match outcome {
+            RefreshOutcome::Rotated { refresh_token, .. } => refresh_token,
+            _ => panic!("expected a rotation"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn replaying_a_rotated_token_revokes_the_family() {
+        for tokens in stores().await {
+            let tokens = tokens.as_ref();
+            let first = RefreshTokenService::issue(tokens, 7, "family-a", 30).await.unwrap();
+            let other = RefreshTokenService::issue(tokens, 7, "family-b", 30).await.unwrap();
+            let second = rotated(RefreshTokenService::rotate(tokens, &first, 30).await.unwrap());
+            assert_ne!(second, first);
+
+            assert!(matches!(RefreshTokenService::rotate(tokens, &first, 30).await.unwrap(), RefreshOutcome::Reused));
+            // The token the rotation issued goes with its family; other logins are untouched
+            assert!(matches!(RefreshTokenService::rotate(tokens, &second, 30).await.unwrap(), RefreshOutcome::Invalid));
+            rotated(RefreshTokenService::rotate(tokens, &other, 30).await.unwrap());
+            assert!(matches!(RefreshTokenService::rotate(tokens, "never-issued", 30).await.unwrap(), RefreshOutcome::Invalid));
+        }
+    }
+
+    // A family revoked between find_refresh and mark_refresh_used must not get a new token
+    #[actix_web::test]
+    async fn revoked_tokens_cannot_be_marked_used() {
+        for tokens in stores().await {
+            let tokens = tokens.as_ref();
+            let raw = RefreshTokenService::issue(tokens, 7, "family-a", 30).await.unwrap();
+            let row = tokens.find_refresh(&hash_token(&raw)).await.unwrap().unwrap();
+            tokens.revoke_refresh_family("family-a").await.unwrap();
+            assert!(!tokens.mark_refresh_used(row.id, row.user_id).await.unwrap());
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use super::{hash_password, hash_passwords, non_blank, Conflict, RefreshRecord, SessionRecord, StaleVersion, TokenStore, UserPage, UserQuery, UserRepository, UserSort};
+use crate::db;
+use crate::models::{CreateUser, SessionView, UpdateUser, User};
+use crate::token::NewSession;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+// Process-local backend (DB_BACKEND=memory) for development and the HTTP tests. Nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryUserRepository {
+    inner: Mutex<MemoryUsers>,
+}
+
+#[derive(Default)]
+struct MemoryUsers {
+    last_id: i32,
+    users: BTreeMap<i32, User>,
+    // Soft-deleted ids; their rows stay in `users` so names and emails remain taken
+    deleted: BTreeSet<i32>,
+}
+
+impl MemoryUsers {
+    fn live(&self) -> impl Iterator<Item = &User> {
+        self.users.values().filter(|u| !self.deleted.contains(&u.id))
+    }
+
+    // What the unique constraints on nombre_usr and email_usr would reject
+    fn conflict(&self, username: Option<&str>, email: Option<&str>, except: Option<i32>) -> Option<Conflict> {
+        let others = || self.users.values().filter(|u| Some(u.id) != except);
+        if username.is_some() && others().any(|u| Some(u.username.as_str()) == username) {
+            return Some(Conflict::Username);
+        }
+        if email.is_some() && others().any(|u| u.email.as_deref() == email) {
+            return Some(Conflict::Email);
+        }
+        None
+    }
+
+    fn insert(&mut self, input: CreateUser, password_hash: String, profile: i32) -> Result<User, Conflict> {
+        if let Some(conflict) = self.conflict(Some(&input.username), non_blank(input.email.as_deref()), None) {
+            return Err(conflict);
+        }
+        self.last_id += 1;
+        let user = User { id: self.last_id, username: input.username, email: input.email, password_hash, profile, version: 1 };
+        self.users.insert(user.id, user.clone());
+        Ok(user)
+    }
+
+    // Filtered and sorted like the SQL backends, before any paging
+    fn matching(&self, query: &UserQuery) -> Vec<User> {
+        let contains = |value: Option<&str>, needle: &Option<String>| match needle {
+            Some(n) => value.is_some_and(|v| v.to_lowercase().contains(&n.to_lowercase())),
+            None => true,
+        };
+        let mut users: Vec<User> = self
+            .live()
+            .filter(|u| contains(Some(&u.username), &query.username) && contains(u.email.as_deref(), &query.email))
+            .filter(|u| query.profile.is_none_or(|p| u.profile == p))
+            .cloned()
+            .collect();
+        // Same keys as UserQuery::order_by: the requested ones, then the id
+        users.sort_by(|a, b| {
+            query
+                .sort
+                .iter()
+                .map(|(field, descending)| {
+                    let ord = match field {
+                        UserSort::Id => a.id.cmp(&b.id),
+                        UserSort::Username => a.username.cmp(&b.username),
+                        UserSort::Email => a.email.cmp(&b.email),
+                        UserSort::Profile => a.profile.cmp(&b.profile),
+                    };
+                    if *descending { ord.reverse() } else { ord }
+                })
+                .fold(std::cmp::Ordering::Equal, |acc, ord| acc.then(ord))
+                .then(a.id.cmp(&b.id))
+        });
+        users
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryUserRepository {
+    async fn create(&self, input: CreateUser, profile: i32, _actor: i32) -> Result<User> {
+        let password_hash = hash_password(input.password.clone()).await?;
+        Ok(self.inner.lock().unwrap().insert(input, password_hash, profile)?)
+    }
+
+    async fn create_many(&self, users: Vec<(CreateUser, i32)>, _actor: i32) -> Result<Vec<User>> {
+        let hashes = hash_passwords(&users).await?;
+        let mut inner = self.inner.lock().unwrap();
+        let last_id = inner.last_id;
+        let mut created = Vec::with_capacity(users.len());
+        for ((input, profile), password_hash) in users.into_iter().zip(hashes) {
+            match inner.insert(input, password_hash, profile) {
+                Ok(user) => created.push(user),
+                // Undo the rows inserted so far, as a rolled back transaction would
+                Err(conflict) => {
+                    inner.users.retain(|id, _| *id <= last_id);
+                    inner.last_id = last_id;
+                    return Err(conflict.into());
+                }
+            }
+        }
+        Ok(created)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<User>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.live().find(|u| u.id == id).cloned())
+    }
+
+    async fn search(&self, query: &UserQuery) -> Result<UserPage> {
+        let users = self.inner.lock().unwrap().matching(query);
+        let total = users.len() as i64;
+        let users = users.into_iter().skip(query.offset as usize).take(query.limit as usize).collect();
+        Ok(UserPage { users, total })
+    }
+
+    // Already in memory, so there is nothing to gain from reading lazily
+    fn export(&self, query: UserQuery) -> BoxStream<'static, Result<User>> {
+        let users = self.inner.lock().unwrap().matching(&query);
+        stream::iter(users.into_iter().map(Ok)).boxed()
+    }
+
+    async fn update(&self, id: i32, input: UpdateUser, _actor: i32, expected_version: Option<i32>) -> Result<Option<User>> {
+        let password_hash = match &input.password {
+            Some(pw) => Some(hash_password(pw.clone()).await?),
+            None => None,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(conflict) = inner.conflict(input.username.as_deref(), non_blank(input.email.as_ref().and_then(Option::as_deref)), Some(id)) {
+            return Err(conflict.into());
+        }
+        if inner.deleted.contains(&id) {
+            return Ok(None);
+        }
+        let user = match inner.users.get_mut(&id) {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+        if expected_version.is_some_and(|v| v != user.version) {
+            return Err(StaleVersion.into());
+        }
+        user.version += 1;
+        if let Some(username) = input.username {
+            user.username = username;
+        }
+        if let Some(email) = input.email {
+            user.email = email;
+        }
+        if let Some(profile) = input.profile {
+            user.profile = profile;
+        }
+        if let Some(password_hash) = password_hash {
+            user.password_hash = password_hash;
+        }
+        Ok(Some(user.clone()))
+    }
+
+    async fn delete(&self, id: i32, _actor: i32) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.users.contains_key(&id) || !inner.deleted.insert(id) {
+            return Ok(false);
+        }
+        if let Some(user) = inner.users.get_mut(&id) {
+            user.version += 1;
+        }
+        Ok(true)
+    }
+
+    async fn restore(&self, id: i32, _actor: i32) -> Result<Option<User>> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.deleted.remove(&id) {
+            return Ok(None);
+        }
+        Ok(inner.users.get_mut(&id).map(|user| {
+            user.version += 1;
+            user.clone()
+        }))
+    }
+
+    // Sessions live in MemoryTokenStore; the delete handler revokes them
+    async fn purge(&self, id: i32) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.deleted.remove(&id);
+        Ok(inner.users.remove(&id).is_some())
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.live().find(|u| u.username == username || u.email.as_deref() == Some(username)).cloned())
+    }
+
+    async fn find_conflict(&self, username: Option<&str>, email: Option<&str>, except: Option<i32>) -> Result<Option<Conflict>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.conflict(username, non_blank(email), except))
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryTokenStore {
+    inner: Mutex<MemoryTokens>,
+}
+
+#[derive(Default)]
+struct MemoryTokens {
+    last_session_id: i32,
+    last_refresh_id: i32,
+    sessions: Vec<SessionRow>,
+    refresh: Vec<RefreshRecord>,
+}
+
+struct SessionRow {
+    id: i32,
+    user_id: i32,
+    token_hash: String,
+    family_id: String,
+    device_name: Option<String>,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    created_at: String,
+    last_seen: String,
+    expires_at: String,
+    expired: bool,
+}
+
+impl MemoryTokens {
+    fn revoke_family(&mut self, family_id: &str) {
+        for r in self.refresh.iter_mut().filter(|r| r.family_id == family_id) {
+            r.revoked = true;
+        }
+    }
+}
+
+// Timestamps use the db::utc_timestamp format, so string comparison orders them chronologically
+#[async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn insert_session(&self, user_id: i32, token_hash: &str, session: &NewSession, expires_at: &str) -> Result<i32> {
+        let now = db::utc_now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_session_id += 1;
+        let id = inner.last_session_id;
+        inner.sessions.push(SessionRow {
+            id,
+            user_id,
+            token_hash: token_hash.to_string(),
+            family_id: session.family_id.clone(),
+            device_name: session.device_name.clone(),
+            user_agent: session.user_agent.clone(),
+            ip_address: session.ip_address.clone(),
+            created_at: now.clone(),
+            last_seen: now,
+            expires_at: expires_at.to_string(),
+            expired: false,
+        });
+        Ok(id)
+    }
+
+    async fn renew_session(&self, user_id: i32, family_id: &str, token_hash: &str, expires_at: &str) -> Result<Option<i32>> {
+        let mut inner = self.inner.lock().unwrap();
+        let session = inner.sessions.iter_mut().find(|s| s.family_id == family_id && s.user_id == user_id && !s.expired);
+        Ok(session.map(|s| {
+            s.token_hash = token_hash.to_string();
+            s.expires_at = expires_at.to_string();
+            s.last_seen = db::utc_now();
+            s.id
+        }))
+    }
+
+    async fn find_session(&self, token_hash: &str) -> Result<Option<SessionRecord>> {
+        let now = db::utc_now();
+        let inner = self.inner.lock().unwrap();
+        let session = inner.sessions.iter().find(|s| s.token_hash == token_hash && !s.expired && s.expires_at > now);
+        Ok(session.map(|s| SessionRecord { session_id: s.id, user_id: s.user_id, token_hash: s.token_hash.clone() }))
+    }
+
+    async fn touch_session(&self, session_id: i32) -> Result<()> {
+        if let Some(s) = self.inner.lock().unwrap().sessions.iter_mut().find(|s| s.id == session_id) {
+            s.last_seen = db::utc_now();
+        }
+        Ok(())
+    }
+
+    async fn list_sessions(&self, user_id: i32) -> Result<Vec<SessionView>> {
+        let now = db::utc_now();
+        let inner = self.inner.lock().unwrap();
+        let mut sessions: Vec<&SessionRow> = inner
+            .sessions
+            .iter()
+            .filter(|s| s.user_id == user_id && !s.expired)
+            .filter(|s| s.expires_at > now || inner.refresh.iter().any(|r| r.family_id == s.family_id && !r.revoked && !r.used && r.expires_at > now))
+            .collect();
+        sessions.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        Ok(sessions
+            .into_iter()
+            .map(|s| SessionView {
+                id: s.id,
+                device_name: s.device_name.clone(),
+                user_agent: s.user_agent.clone(),
+                ip_address: s.ip_address.clone(),
+                created_at: s.created_at.clone(),
+                last_seen: Some(s.last_seen.clone()),
+                current: false,
+            })
+            .collect())
+    }
+
+    async fn revoke_session_by_token(&self, token_hash: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let family = inner.sessions.iter_mut().find(|s| s.token_hash == token_hash).map(|s| {
+            s.expired = true;
+            s.family_id.clone()
+        });
+        if let Some(family_id) = family {
+            inner.revoke_family(&family_id);
+        }
+        Ok(())
+    }
+
+    async fn revoke_session(&self, user_id: i32, session_id: i32) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let family = inner.sessions.iter_mut().find(|s| s.id == session_id && s.user_id == user_id).map(|s| {
+            s.expired = true;
+            s.family_id.clone()
+        });
+        match family {
+            Some(family_id) => {
+                inner.revoke_family(&family_id);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn revoke_all_sessions(&self, user_id: i32) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        for r in inner.refresh.iter_mut().filter(|r| r.user_id == user_id) {
+            r.revoked = true;
+        }
+        for s in inner.sessions.iter_mut().filter(|s| s.user_id == user_id) {
+            s.expired = true;
+        }
+        Ok(())
+    }
+
+    async fn insert_refresh(&self, user_id: i32, family_id: &str, token_hash: &str, expires_at: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_refresh_id += 1;
+        let id = inner.last_refresh_id;
+        inner.refresh.push(RefreshRecord {
+            id,
+            user_id,
+            family_id: family_id.to_string(),
+            token_hash: token_hash.to_string(),
+            used: false,
+            revoked: false,
+            expires_at: expires_at.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn find_refresh(&self, token_hash: &str) -> Result<Option<RefreshRecord>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.refresh.iter().find(|r| r.token_hash == token_hash).cloned())
+    }
+
+    async fn mark_refresh_used(&self, refresh_id: i32, _user_id: i32) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.refresh.iter_mut().find(|r| r.id == refresh_id && !r.used && !r.revoked) {
+            Some(r) => {
+                r.used = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn revoke_refresh_family(&self, family_id: &str) -> Result<()> {
+        self.inner.lock().unwrap().revoke_family(family_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+use crate::models::{CreateUser, SessionView, UpdateUser, User};
+use crate::token::NewSession;
+use anyhow::Result;
+use async_trait::async_trait;
+use bcrypt::{hash, verify, DEFAULT_COST};
+use futures::channel::mpsc;
+use futures::stream::BoxStream;
+use futures::{SinkExt, Stream, StreamExt};
+use std::future::Future;
+
+pub mod memory;
+pub mod mssql;
+pub mod postgres;
+pub mod sql;
+pub mod sqlite;
+
+pub use memory::{MemoryTokenStore, MemoryUserRepository};
+pub use mssql::{MssqlTokenStore, MssqlUserRepository};
+pub use postgres::{PostgresTokenStore, PostgresUserRepository};
+pub use sqlite::{SqliteTokenStore, SqliteUserRepository};
+
+// Storage for `usuarios`. Handlers receive it as `web::Data<dyn UserRepository>` so the backend can be swapped.
+// `actor` is the authenticated user performing the change and ends up in the audit columns.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn create(&self, input: CreateUser, profile: i32, actor: i32) -> Result<User>;
+    // All or nothing: the users are inserted in order inside one transaction, and any failure (a Conflict
+    // included) leaves the table as it was. Returns the created users in the same order.
+    async fn create_many(&self, users: Vec<(CreateUser, i32)>, actor: i32) -> Result<Vec<User>>;
+    async fn get(&self, id: i32) -> Result<Option<User>>;
+    // One page of the users matching the filters, plus how many match in total
+    async fn search(&self, query: &UserQuery) -> Result<UserPage>;
+    // Every user matching the filters, in the query's order (offset and limit are ignored), read as the
+    // response consumes them rather than collected first
+    fn export(&self, query: UserQuery) -> BoxStream<'static, Result<User>>;
+    // With `expected_version`, the write only happens if the row is still at that version; otherwise it fails
+    // with StaleVersion. None if there is no such (live) user.
+    async fn update(&self, id: i32, input: UpdateUser, actor: i32, expected_version: Option<i32>) -> Result<Option<User>>;
+    // Soft delete: sets deleted_at, after which get/list/search/find_by_username no longer see the user.
+    // False if there is no such user or it is already deleted.
+    async fn delete(&self, id: i32, actor: i32) -> Result<bool>;
+    // Clears deleted_at; None unless the user exists and is deleted
+    async fn restore(&self, id: i32, actor: i32) -> Result<Option<User>>;
+    // Removes the row for good, deleted or not, along with its usertoken and refreshtoken rows
+    async fn purge(&self, id: i32) -> Result<bool>;
+    // Matches either the username or the email, as login accepts both
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>>;
+    // Which of the given values another user (anyone but `except`) already has; the username is reported first.
+    // Deleted users count, since they keep their username and email until purged.
+    async fn find_conflict(&self, username: Option<&str>, email: Option<&str>, except: Option<i32>) -> Result<Option<Conflict>>;
+}
+
+// A username or email that belongs to another user. Repositories return it inside anyhow::Error and handlers
+// downcast it into a 409.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    Username,
+    Email,
+}
+
+impl Conflict {
+    pub fn code(self) -> &'static str {
+        match self {
+            Conflict::Username => "username_taken",
+            Conflict::Email => "email_taken",
+        }
+    }
+
+    // The payload field that holds the taken value
+    pub fn field(self) -> &'static str {
+        match self {
+            Conflict::Username => "username",
+            Conflict::Email => "email",
+        }
+    }
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Conflict::Username => write!(f, "username already exists"),
+            Conflict::Email => write!(f, "email already exists"),
+        }
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+// The user changed since the version the caller based its update on; handlers answer 412
+#[derive(Debug)]
+pub struct StaleVersion;
+
+impl std::fmt::Display for StaleVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the user was modified by someone else")
+    }
+}
+
+impl std::error::Error for StaleVersion {}
+
+// Unique-constraint violations on usuarios become a Conflict. The email index is named UX_usuarios_email
+// (migration 0004), so any violation not mentioning "email" is the username constraint.
+// SQL Server reports no code through sqlx, hence the message check.
+pub(crate) fn map_unique_violation(e: sqlx::Error) -> anyhow::Error {
+    if let sqlx::Error::Database(db) = &e {
+        let message = db.message().to_ascii_lowercase();
+        let unique = matches!(db.code().as_deref(), Some("23505" | "2067" | "1555")) || message.contains("duplicate key");
+        if unique {
+            // Match the column / index name only: SQL Server also quotes the duplicate value in the message
+            let email = message.contains("email_usr") || message.contains("ux_usuarios_email");
+            return if email { Conflict::Email } else { Conflict::Username }.into();
+        }
+    }
+    e.into()
+}
+
+// bcrypt is slow on purpose, so it always runs on the blocking pool instead of stalling an async worker
+pub(crate) async fn hash_password(password: String) -> Result<String> {
+    Ok(tokio::task::spawn_blocking(move || hash(password, DEFAULT_COST)).await??)
+}
+
+// A malformed stored hash is a mismatch, not an error
+pub(crate) async fn verify_password(password: String, password_hash: String) -> Result<bool> {
+    Ok(tokio::task::spawn_blocking(move || verify(password, &password_hash).unwrap_or(false)).await?)
+}
+
+// A batch is hashed in parallel rather than one by one (and before any transaction is opened)
+pub(crate) async fn hash_passwords(users: &[(CreateUser, i32)]) -> Result<Vec<String>> {
+    futures::future::try_join_all(users.iter().map(|(input, _)| hash_password(input.password.clone()))).await
+}
+
+// Blank emails are stored but never unique
+pub(crate) fn non_blank(email: Option<&str>) -> Option<&str> {
+    email.filter(|e| !e.is_empty())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserSort {
+    Id,
+    Username,
+    Email,
+    Profile,
+}
+
+impl UserSort {
+    // `?sort=username,-id`: comma-separated fields, a leading '-' sorts descending. Returns (field, descending).
+    pub fn parse_list(sort: &str) -> Result<Vec<(UserSort, bool)>, String> {
+        sort.split(',')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .map(|f| {
+                let (name, descending) = match f.strip_prefix('-') {
+                    Some(name) => (name, true),
+                    None => (f, false),
+                };
+                let field = match name {
+                    "id" => UserSort::Id,
+                    "username" => UserSort::Username,
+                    "email" => UserSort::Email,
+                    "profile" => UserSort::Profile,
+                    other => return Err(format!("cannot sort by '{}'", other)),
+                };
+                Ok((field, descending))
+            })
+            .collect()
+    }
+
+    fn column(self) -> &'static str {
+        match self {
+            UserSort::Id => "codusr_usr",
+            UserSort::Username => "nombre_usr",
+            UserSort::Email => "email_usr",
+            UserSort::Profile => "codperf_usr",
+        }
+    }
+}
+
+// Filters and page for UserRepository::search. Username and email match as case-insensitive substrings.
+pub struct UserQuery {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub profile: Option<i32>,
+    pub sort: Vec<(UserSort, bool)>,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+impl UserQuery {
+    // Columns come from the UserSort whitelist, never from the request. The id is always the last key so
+    // that pages do not overlap when the other keys tie.
+    pub(crate) fn order_by(&self) -> String {
+        let mut keys: Vec<String> = self.sort.iter().map(|(f, desc)| format!("{}{}", f.column(), if *desc { " DESC" } else { "" })).collect();
+        if !self.sort.iter().any(|(f, _)| *f == UserSort::Id) {
+            keys.push("codusr_usr".into());
+        }
+        keys.join(", ")
+    }
+}
+
+// Rows an export may read ahead of the response. The query task waits while the buffer is full, so a slow
+// client holds back the database cursor instead of growing memory.
+const EXPORT_BUFFER: usize = 256;
+
+// Runs `produce` on its own task and streams what it sends. Sending fails once the response is dropped
+// (the client went away), which is the producer's cue to stop.
+pub(crate) fn export_stream<F, Fut>(produce: F) -> BoxStream<'static, Result<User>>
+where
+    F: FnOnce(mpsc::Sender<Result<User>>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(EXPORT_BUFFER);
+    tokio::spawn(produce(tx));
+    rx.boxed()
+}
+
+// Passes rows from a sqlx fetch on until it ends, fails or nobody is listening
+pub(crate) async fn forward_rows<S: Stream<Item = sqlx::Result<User>> + Unpin>(mut rows: S, mut tx: mpsc::Sender<Result<User>>) {
+    while let Some(row) = rows.next().await {
+        let failed = row.is_err();
+        if tx.send(row.map_err(Into::into)).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+// `%value%` for LIKE ... ESCAPE '\', with the wildcards in the value taken literally
+pub(crate) fn like_pattern(value: &str) -> String {
+    format!("%{}%", value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+}
+
+pub struct UserPage {
+    pub users: Vec<User>,
+    pub total: i64,
+}
+
+// The row sp_usuarios_update would write: fields missing from the input keep their current value
+pub(crate) async fn apply_update(current: User, input: UpdateUser) -> Result<User> {
+    let password_hash = match input.password {
+        Some(pw) => hash_password(pw).await?,
+        None => current.password_hash,
+    };
+    Ok(User {
+        id: current.id,
+        username: input.username.unwrap_or(current.username),
+        email: input.email.unwrap_or(current.email),
+        password_hash,
+        profile: input.profile.unwrap_or(current.profile),
+        version: current.version,
+    })
+}
+
+// A usertoken row as seen by authentication
+pub struct SessionRecord {
+    pub session_id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+}
+
+// A refreshtoken row; expires_at uses the db::utc_timestamp format
+#[derive(Clone, sqlx::FromRow)]
+pub struct RefreshRecord {
+    #[sqlx(rename = "RefreshID")]
+    pub id: i32,
+    #[sqlx(rename = "UserID")]
+    pub user_id: i32,
+    #[sqlx(rename = "FamilyID")]
+    pub family_id: String,
+    #[sqlx(rename = "token")]
+    pub token_hash: String,
+    pub used: bool,
+    pub revoked: bool,
+    #[sqlx(rename = "expiredDate")]
+    pub expires_at: String,
+}
+
+// Storage for sessions (`usertoken`) and refresh tokens (`refreshtoken`). Tokens arrive already hashed;
+// TokenService and RefreshTokenService own generation, expiry and the reuse rules.
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    async fn insert_session(&self, user_id: i32, token_hash: &str, session: &NewSession, expires_at: &str) -> Result<i32>;
+    // Points the live session of a refresh family at a new access token; None if that session was revoked
+    async fn renew_session(&self, user_id: i32, family_id: &str, token_hash: &str, expires_at: &str) -> Result<Option<i32>>;
+    // Active session only: not revoked and not past its expiry
+    async fn find_session(&self, token_hash: &str) -> Result<Option<SessionRecord>>;
+    // LastSeen is informational, so backends may skip writes made less than a minute apart
+    async fn touch_session(&self, session_id: i32) -> Result<()>;
+    // Sessions still usable: the access token is valid or the refresh family can mint a new one
+    async fn list_sessions(&self, user_id: i32) -> Result<Vec<SessionView>>;
+    // Logout by token. Unknown or already revoked tokens are not an error; the refresh family goes with the session.
+    async fn revoke_session_by_token(&self, token_hash: &str) -> Result<()>;
+    // Ends one session of the given user together with its refresh family; false if it is not theirs
+    async fn revoke_session(&self, user_id: i32, session_id: i32) -> Result<bool>;
+    // Every session and refresh token of the user
+    async fn revoke_all_sessions(&self, user_id: i32) -> Result<()>;
+    async fn insert_refresh(&self, user_id: i32, family_id: &str, token_hash: &str, expires_at: &str) -> Result<()>;
+    async fn find_refresh(&self, token_hash: &str) -> Result<Option<RefreshRecord>>;
+    // Sets `used` only if it was still unset and the token not revoked, so exactly one concurrent rotation wins
+    // and none does once the family has been revoked since the token was read
+    async fn mark_refresh_used(&self, refresh_id: i32, user_id: i32) -> Result<bool>;
+    async fn revoke_refresh_family(&self, family_id: &str) -> Result<()>;
+}
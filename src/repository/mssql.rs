@@ -0,0 +1,297 @@
+use super::{Conflict, RefreshRecord, SessionRecord, TokenStore, UserPage, UserQuery, UserRepository};
+use crate::db;
+use crate::models::{CreateUser, SessionView, UpdateUser, User};
+use crate::token::NewSession;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use sqlx::mssql::MssqlArguments;
+use sqlx::query::{Query, QueryAs};
+use sqlx::{Mssql, Pool};
+
+// SQL Server backend: the queries and stored procedure calls live in db.rs
+pub struct MssqlUserRepository {
+    pool: Pool<Mssql>,
+}
+
+impl MssqlUserRepository {
+    pub fn new(pool: Pool<Mssql>) -> Self {
+        MssqlUserRepository { pool }
+    }
+}
+
+#[async_trait]
+impl UserRepository for MssqlUserRepository {
+    async fn create(&self, input: CreateUser, profile: i32, actor: i32) -> Result<User> {
+        db::create_user(&self.pool, input, profile, actor).await
+    }
+
+    async fn create_many(&self, users: Vec<(CreateUser, i32)>, actor: i32) -> Result<Vec<User>> {
+        db::create_users(&self.pool, users, actor).await
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<User>> {
+        db::get_user(&self.pool, id).await
+    }
+
+    fn export(&self, query: UserQuery) -> BoxStream<'static, Result<User>> {
+        db::export_users(self.pool.clone(), query)
+    }
+
+    async fn search(&self, query: &UserQuery) -> Result<UserPage> {
+        db::search_users(&self.pool, query).await
+    }
+
+    async fn update(&self, id: i32, input: UpdateUser, actor: i32, expected_version: Option<i32>) -> Result<Option<User>> {
+        db::update_user(&self.pool, id, input, actor, expected_version).await
+    }
+
+    async fn delete(&self, id: i32, actor: i32) -> Result<bool> {
+        db::delete_user(&self.pool, id, actor).await
+    }
+
+    async fn restore(&self, id: i32, actor: i32) -> Result<Option<User>> {
+        db::restore_user(&self.pool, id, actor).await
+    }
+
+    async fn purge(&self, id: i32) -> Result<bool> {
+        db::purge_user(&self.pool, id).await
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>> {
+        db::find_by_username(&self.pool, username).await
+    }
+
+    async fn find_conflict(&self, username: Option<&str>, email: Option<&str>, except: Option<i32>) -> Result<Option<Conflict>> {
+        db::find_conflict(&self.pool, username, email, except).await
+    }
+}
+
+// Every statement that receives a token binds it as a parameter; the SQL text never contains caller data
+type MssqlQuery<'q> = Query<'q, Mssql, MssqlArguments>;
+
+const LOGOUT_SQL: &str = "EXEC SP_LOGOUT @token = @p1";
+const FIND_SESSION_SQL: &str = "SELECT TokenID, UserID, token FROM usertoken WHERE token = @p1 AND expired = 0 AND expiredDate > @p2";
+const INSERT_SESSION_SQL: &str = r#"
+    INSERT INTO usertoken (UserID, token, FamilyID, DeviceName, UserAgent, IpAddress, createdDate, LastSeen, expiredDate, expired, usercrea, usermod, fechcrea, fechmod)
+    OUTPUT INSERTED.TokenID
+    VALUES (@p1, @p2, @p3, @p4, @p5, @p6, @p7, @p7, @p8, 0, @p1, @p1, @p7, @p7)
+"#;
+const RENEW_SESSION_SQL: &str = r#"
+    UPDATE usertoken
+    SET token = @p1, expiredDate = @p2, LastSeen = @p3, usermod = @p5, fechmod = @p3
+    OUTPUT INSERTED.TokenID
+    WHERE FamilyID = @p4 AND UserID = @p5 AND expired = 0
+"#;
+const REVOKE_REFRESH_BY_TOKEN_SQL: &str = "UPDATE refreshtoken SET revoked = 1, fechmod = @p2 WHERE revoked = 0 AND FamilyID IN (SELECT FamilyID FROM usertoken WHERE token = @p1)";
+const INSERT_REFRESH_SQL: &str = "INSERT INTO refreshtoken (UserID, FamilyID, token, createdDate, expiredDate, used, revoked, usercrea, usermod, fechcrea, fechmod) \
+     VALUES (@p1, @p2, @p3, @p5, @p4, 0, 0, @p1, @p1, @p5, @p5)";
+const FIND_REFRESH_SQL: &str = "SELECT RefreshID, UserID, FamilyID, token, CAST(used AS bit) AS used, CAST(revoked AS bit) AS revoked, CONVERT(varchar, expiredDate, 120) AS expiredDate FROM refreshtoken WHERE token = @p1";
+const REVOKE_REFRESH_FAMILY_SQL: &str = "UPDATE refreshtoken SET revoked = 1, fechmod = @p2 WHERE FamilyID = @p1 AND revoked = 0";
+
+fn revoke_token_query(token_hash: &str) -> MssqlQuery<'_> {
+    sqlx::query(LOGOUT_SQL).bind(token_hash)
+}
+
+fn find_session_query(token_hash: &str, now: String) -> QueryAs<'_, Mssql, (i32, i32, String), MssqlArguments> {
+    sqlx::query_as::<_, (i32, i32, String)>(FIND_SESSION_SQL).bind(token_hash).bind(now)
+}
+
+fn insert_session_query<'q>(user_id: i32, token_hash: &'q str, session: &'q NewSession, now: String, expired_date: &'q str) -> QueryAs<'q, Mssql, (i32,), MssqlArguments> {
+    sqlx::query_as::<_, (i32,)>(INSERT_SESSION_SQL)
+        .bind(user_id)
+        .bind(token_hash)
+        .bind(session.family_id.as_str())
+        .bind(session.device_name.as_deref())
+        .bind(session.user_agent.as_deref())
+        .bind(session.ip_address.as_deref())
+        .bind(now)
+        .bind(expired_date)
+}
+
+fn renew_session_query<'q>(user_id: i32, family_id: &'q str, token_hash: &'q str, expires_at: &'q str, now: String) -> QueryAs<'q, Mssql, (i32,), MssqlArguments> {
+    sqlx::query_as::<_, (i32,)>(RENEW_SESSION_SQL)
+        .bind(token_hash)
+        .bind(expires_at)
+        .bind(now)
+        .bind(family_id)
+        .bind(user_id)
+}
+
+fn revoke_refresh_by_token_query(token_hash: &str, now: String) -> MssqlQuery<'_> {
+    sqlx::query(REVOKE_REFRESH_BY_TOKEN_SQL).bind(token_hash).bind(now)
+}
+
+fn insert_refresh_query<'q>(user_id: i32, family_id: &'q str, token_hash: &'q str, expires_at: &'q str, now: String) -> MssqlQuery<'q> {
+    sqlx::query(INSERT_REFRESH_SQL)
+        .bind(user_id)
+        .bind(family_id)
+        .bind(token_hash)
+        .bind(expires_at)
+        .bind(now)
+}
+
+fn find_refresh_query(token_hash: &str) -> QueryAs<'_, Mssql, RefreshRecord, MssqlArguments> {
+    sqlx::query_as::<_, RefreshRecord>(FIND_REFRESH_SQL).bind(token_hash)
+}
+
+fn revoke_refresh_family_query(family_id: &str, now: String) -> MssqlQuery<'_> {
+    sqlx::query(REVOKE_REFRESH_FAMILY_SQL).bind(family_id).bind(now)
+}
+
+// usertoken / refreshtoken on SQL Server
+pub struct MssqlTokenStore {
+    pool: Pool<Mssql>,
+}
+
+impl MssqlTokenStore {
+    pub fn new(pool: Pool<Mssql>) -> Self {
+        MssqlTokenStore { pool }
+    }
+}
+
+#[async_trait]
+impl TokenStore for MssqlTokenStore {
+    async fn insert_session(&self, user_id: i32, token_hash: &str, session: &NewSession, expires_at: &str) -> Result<i32> {
+        let (session_id,) = insert_session_query(user_id, token_hash, session, db::utc_now(), expires_at).fetch_one(&self.pool).await?;
+        Ok(session_id)
+    }
+
+    async fn renew_session(&self, user_id: i32, family_id: &str, token_hash: &str, expires_at: &str) -> Result<Option<i32>> {
+        let row = renew_session_query(user_id, family_id, token_hash, expires_at, db::utc_now()).fetch_optional(&self.pool).await?;
+        Ok(row.map(|(session_id,)| session_id))
+    }
+
+    // expiredDate is written in UTC, like every other timestamp
+    async fn find_session(&self, token_hash: &str) -> Result<Option<SessionRecord>> {
+        let row = find_session_query(token_hash, db::utc_now()).fetch_optional(&self.pool).await?;
+        Ok(row.map(|(session_id, user_id, token_hash)| SessionRecord { session_id, user_id, token_hash }))
+    }
+
+    async fn touch_session(&self, session_id: i32) -> Result<()> {
+        let now = chrono::Utc::now();
+        sqlx::query("UPDATE usertoken SET LastSeen = @p2 WHERE TokenID = @p1 AND (LastSeen IS NULL OR LastSeen < @p3)")
+            .bind(session_id)
+            .bind(db::utc_timestamp(now))
+            .bind(db::utc_timestamp(now - chrono::Duration::minutes(1)))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_sessions(&self, user_id: i32) -> Result<Vec<SessionView>> {
+        let sessions = sqlx::query_as::<_, SessionView>(
+            "SELECT t.TokenID, t.DeviceName, t.UserAgent, t.IpAddress, CONVERT(varchar, t.createdDate, 120) AS createdDate, CONVERT(varchar, t.LastSeen, 120) AS LastSeen \
+             FROM usertoken t \
+             WHERE t.UserID = @p1 AND t.expired = 0 \
+             AND (t.expiredDate > @p2 OR EXISTS (SELECT 1 FROM refreshtoken r WHERE r.FamilyID = t.FamilyID AND r.revoked = 0 AND r.used = 0 AND r.expiredDate > @p2)) \
+             ORDER BY t.LastSeen DESC"
+        )
+        .bind(user_id)
+        .bind(db::utc_now())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(sessions)
+    }
+
+    async fn revoke_session_by_token(&self, token_hash: &str) -> Result<()> {
+        revoke_refresh_by_token_query(token_hash, db::utc_now()).execute(&self.pool).await?;
+        revoke_token_query(token_hash).fetch_optional(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn revoke_session(&self, user_id: i32, session_id: i32) -> Result<bool> {
+        let now = db::utc_now();
+        let res = sqlx::query("UPDATE usertoken SET expired = 1, usermod = @p2, fechmod = @p3 WHERE TokenID = @p1 AND UserID = @p2")
+            .bind(session_id)
+            .bind(user_id)
+            .bind(now.clone())
+            .execute(&self.pool)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query("UPDATE refreshtoken SET revoked = 1, usermod = @p2, fechmod = @p3 WHERE revoked = 0 AND FamilyID IN (SELECT FamilyID FROM usertoken WHERE TokenID = @p1)")
+            .bind(session_id)
+            .bind(user_id)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn revoke_all_sessions(&self, user_id: i32) -> Result<()> {
+        let now = db::utc_now();
+        sqlx::query("UPDATE refreshtoken SET revoked = 1, usermod = @p1, fechmod = @p2 WHERE UserID = @p1 AND revoked = 0")
+            .bind(user_id)
+            .bind(now.clone())
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("UPDATE usertoken SET expired = 1, usermod = @p1, fechmod = @p2 WHERE UserID = @p1 AND expired = 0")
+            .bind(user_id)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_refresh(&self, user_id: i32, family_id: &str, token_hash: &str, expires_at: &str) -> Result<()> {
+        insert_refresh_query(user_id, family_id, token_hash, expires_at, db::utc_now()).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn find_refresh(&self, token_hash: &str) -> Result<Option<RefreshRecord>> {
+        let row = find_refresh_query(token_hash).fetch_optional(&self.pool).await?;
+        Ok(row)
+    }
+
+    async fn mark_refresh_used(&self, refresh_id: i32, user_id: i32) -> Result<bool> {
+        let res = sqlx::query("UPDATE refreshtoken SET used = 1, usermod = @p2, fechmod = @p3 WHERE RefreshID = @p1 AND used = 0 AND revoked = 0")
+            .bind(refresh_id)
+            .bind(user_id)
+            .bind(db::utc_now())
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    async fn revoke_refresh_family(&self, family_id: &str) -> Result<()> {
+        revoke_refresh_family_query(family_id, db::utc_now()).execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::HOSTILE_TOKENS;
+    use sqlx::Execute;
+
+    // The SQL text must be the fixed statement with the token only among the bound arguments
+    fn assert_bound<'q, E: Execute<'q, Mssql>>(mut query: E, token: &str) {
+        assert!(!query.sql().contains(token), "token leaked into SQL text: {}", token);
+        assert!(query.sql().contains("@p1"), "statement takes no parameters");
+        assert!(query.take_arguments().is_some(), "token was not bound as a parameter: {}", token);
+    }
+
+    #[test]
+    fn session_queries_bind_the_token() {
+        for token in HOSTILE_TOKENS {
+            let session = NewSession { family_id: token.to_string(), device_name: Some(token.to_string()), user_agent: Some(token.to_string()), ip_address: Some(token.to_string()) };
+            assert_bound(insert_session_query(1, token, &session, "2024-01-01 00:00:00".into(), "2024-01-01 00:10:00"), token);
+            assert_bound(find_session_query(token, "2024-01-01 00:00:00".into()), token);
+            assert_bound(renew_session_query(1, token, token, "2024-01-01 00:10:00", "2024-01-01 00:00:00".into()), token);
+            assert_bound(revoke_token_query(token), token);
+            assert_bound(revoke_refresh_by_token_query(token, "2024-01-01 00:00:00".into()), token);
+        }
+    }
+
+    #[test]
+    fn refresh_queries_bind_the_token() {
+        for token in HOSTILE_TOKENS {
+            assert_bound(insert_refresh_query(1, token, token, "2024-01-31 00:00:00", "2024-01-01 00:00:00".into()), token);
+            assert_bound(find_refresh_query(token), token);
+            assert_bound(revoke_refresh_family_query(token, "2024-01-01 00:00:00".into()), token);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use super::sql::{Dialect, SqlTokenStore, SqlUserRepository};
+use crate::config::Settings;
+use anyhow::Result;
+use sqlx::postgres::{PgPoolOptions, PgQueryResult};
+use sqlx::{Pool, Postgres};
+use std::borrow::Cow;
+use std::time::Duration;
+
+// Tables come from migrations/postgres. Identifiers there are unquoted, so Postgres folds them to lower case;
+// queries alias them back where a FromRow struct expects the SQL Server spelling.
+// Timestamps are bound as db::utc_timestamp strings and cast on the way in and out.
+pub type PostgresUserRepository = SqlUserRepository<Postgres>;
+pub type PostgresTokenStore = SqlTokenStore<Postgres>;
+
+pub async fn connect(settings: &Settings, url: &str) -> Result<Pool<Postgres>> {
+    let pool = PgPoolOptions::new()
+        .min_connections(settings.db.min_connections)
+        .max_connections(settings.db.max_connections)
+        .acquire_timeout(Duration::from_secs(settings.db.acquire_timeout_secs))
+        .connect(url)
+        .await?;
+    Ok(pool)
+}
+
+// The shared queries are already written for Postgres
+impl Dialect for Postgres {
+    fn sql(query: &str) -> Cow<'_, str> {
+        Cow::Borrowed(query)
+    }
+
+    fn timestamp_text(column: &str) -> String {
+        format!("to_char({}, 'YYYY-MM-DD HH24:MI:SS')", column)
+    }
+
+    fn rows_affected(result: &PgQueryResult) -> u64 {
+        result.rows_affected()
+    }
+}
@@ -0,0 +1,473 @@
+use super::{apply_update, export_stream, forward_rows, hash_passwords, like_pattern, map_unique_violation, non_blank, Conflict, RefreshRecord, SessionRecord, StaleVersion, TokenStore, UserPage, UserQuery, UserRepository};
+use crate::db;
+use crate::models::{CreateUser, SessionView, UpdateUser, User};
+use crate::token::NewSession;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use sqlx::database::HasArguments;
+use sqlx::{Database, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+use std::borrow::Cow;
+
+// The SQLite and PostgreSQL backends share the queries below; each backend module only says how its
+// database spells them. The text is written for Postgres: $N placeholders, `::` casts where a parameter's type
+// cannot be inferred, ILIKE and TRUE/FALSE flags. Timestamps are bound as db::utc_timestamp strings.
+pub trait Dialect: Database {
+    // This backend's version of one of the queries below
+    fn sql(query: &str) -> Cow<'_, str>;
+    // Reads a timestamp column back in the db::utc_timestamp format
+    fn timestamp_text(column: &str) -> String;
+    fn rows_affected(result: &Self::QueryResult) -> u64;
+}
+
+const USER_COLUMNS: &str = "codusr_usr, nombre_usr, email_usr, contrasena_usr, codperf_usr, row_version";
+// Live users matching UserQuery's filters: $1 username pattern, $2 email pattern, $3 profile
+const USER_FILTER: &str = "WHERE deleted_at IS NULL AND ($1::text IS NULL OR nombre_usr ILIKE $1 ESCAPE '\\') AND ($2::text IS NULL OR email_usr ILIKE $2 ESCAPE '\\') AND ($3::int IS NULL OR codperf_usr = $3)";
+
+pub struct SqlUserRepository<DB: Database> {
+    pool: Pool<DB>,
+}
+
+impl<DB: Database> SqlUserRepository<DB> {
+    pub fn new(pool: Pool<DB>) -> Self {
+        SqlUserRepository { pool }
+    }
+}
+
+// Mirrors sp_usuarios_insert / update / delete / restore / purge
+#[async_trait]
+impl<DB: Dialect> UserRepository for SqlUserRepository<DB>
+where
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> <DB as HasArguments<'q>>::Arguments: IntoArguments<'q, DB>,
+    for<'q> i32: Encode<'q, DB> + Type<DB>,
+    for<'q> i64: Encode<'q, DB> + Type<DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+    for<'q> &'q str: Encode<'q, DB> + Type<DB>,
+    for<'q> Option<i32>: Encode<'q, DB>,
+    for<'q> Option<String>: Encode<'q, DB>,
+    for<'q> Option<&'q str>: Encode<'q, DB>,
+    for<'r> User: FromRow<'r, DB::Row>,
+    for<'r> (i32,): FromRow<'r, DB::Row>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+{
+    // A single create is a one-row batch
+    async fn create(&self, input: CreateUser, profile: i32, actor: i32) -> Result<User> {
+        let mut created = self.create_many(vec![(input, profile)], actor).await?;
+        Ok(created.remove(0))
+    }
+
+    async fn create_many(&self, users: Vec<(CreateUser, i32)>, actor: i32) -> Result<Vec<User>> {
+        let hashes = hash_passwords(&users).await?;
+        let sql = DB::sql(&format!(
+            "INSERT INTO usuarios (nombre_usr, email_usr, codperf_usr, contrasena_usr, usercrea, usermod, fechcrea, fechmod) \
+             VALUES ($1, $2, $3, $4, $5, $5, $6::timestamp, $6::timestamp) RETURNING {}",
+            USER_COLUMNS
+        ))
+        .into_owned();
+        let now = db::utc_now();
+        // Dropping the transaction on an error rolls it back
+        let mut tx = self.pool.begin().await?;
+        let mut created = Vec::with_capacity(users.len());
+        for ((input, profile), password_hash) in users.into_iter().zip(hashes) {
+            let user = sqlx::query_as::<_, User>(&sql)
+                .bind(input.username)
+                .bind(input.email)
+                .bind(profile)
+                .bind(password_hash)
+                .bind(actor)
+                .bind(now.as_str())
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(map_unique_violation)?;
+            created.push(user);
+        }
+        tx.commit().await?;
+        Ok(created)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(&DB::sql(&format!("SELECT {} FROM usuarios WHERE codusr_usr = $1 AND deleted_at IS NULL", USER_COLUMNS)))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(user)
+    }
+
+    async fn search(&self, query: &UserQuery) -> Result<UserPage> {
+        let username = query.username.as_deref().map(like_pattern);
+        let email = query.email.as_deref().map(like_pattern);
+        let total: i64 = sqlx::query_scalar(&DB::sql(&format!("SELECT COUNT(*) FROM usuarios {}", USER_FILTER)))
+            .bind(username.as_deref())
+            .bind(email.as_deref())
+            .bind(query.profile)
+            .fetch_one(&self.pool)
+            .await?;
+        let users = sqlx::query_as::<_, User>(&DB::sql(&format!("SELECT {} FROM usuarios {} ORDER BY {} LIMIT $4 OFFSET $5", USER_COLUMNS, USER_FILTER, query.order_by())))
+            .bind(username.as_deref())
+            .bind(email.as_deref())
+            .bind(query.profile)
+            .bind(query.limit)
+            .bind(query.offset)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(UserPage { users, total })
+    }
+
+    fn export(&self, query: UserQuery) -> BoxStream<'static, Result<User>> {
+        let pool = self.pool.clone();
+        export_stream(move |tx| async move {
+            let sql = DB::sql(&format!("SELECT {} FROM usuarios {} ORDER BY {}", USER_COLUMNS, USER_FILTER, query.order_by())).into_owned();
+            let rows = sqlx::query_as::<_, User>(&sql)
+                .bind(query.username.as_deref().map(like_pattern))
+                .bind(query.email.as_deref().map(like_pattern))
+                .bind(query.profile)
+                .fetch(&pool);
+            forward_rows(rows, tx).await;
+        })
+    }
+
+    // usercrea/fechcrea are left untouched
+    async fn update(&self, id: i32, input: UpdateUser, actor: i32, expected_version: Option<i32>) -> Result<Option<User>> {
+        let current = match self.get(id).await? {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+        if expected_version.is_some_and(|v| v != current.version) {
+            return Err(StaleVersion.into());
+        }
+        let user = apply_update(current, input).await?;
+        let res = sqlx::query(&DB::sql(
+            "UPDATE usuarios SET nombre_usr = $2, email_usr = $3, codperf_usr = $4, contrasena_usr = $5, usermod = $6, fechmod = $7::timestamp, row_version = row_version + 1 \
+             WHERE codusr_usr = $1 AND deleted_at IS NULL AND ($8::int IS NULL OR row_version = $8)",
+        ))
+        .bind(id)
+        .bind(user.username)
+        .bind(user.email)
+        .bind(user.profile)
+        .bind(user.password_hash)
+        .bind(actor)
+        .bind(db::utc_now())
+        .bind(expected_version)
+        .execute(&self.pool)
+        .await
+        .map_err(map_unique_violation)?;
+        // Deleted, or changed by someone else, between the read and the write
+        if DB::rows_affected(&res) == 0 {
+            return match self.get(id).await? {
+                Some(_) if expected_version.is_some() => Err(StaleVersion.into()),
+                _ => Ok(None),
+            };
+        }
+        self.get(id).await
+    }
+
+    async fn delete(&self, id: i32, actor: i32) -> Result<bool> {
+        let res = sqlx::query(&DB::sql("UPDATE usuarios SET deleted_at = $2::timestamp, row_version = row_version + 1, usermod = $3, fechmod = $2::timestamp WHERE codusr_usr = $1 AND deleted_at IS NULL"))
+            .bind(id)
+            .bind(db::utc_now())
+            .bind(actor)
+            .execute(&self.pool)
+            .await?;
+        Ok(DB::rows_affected(&res) > 0)
+    }
+
+    async fn restore(&self, id: i32, actor: i32) -> Result<Option<User>> {
+        let res = sqlx::query(&DB::sql("UPDATE usuarios SET deleted_at = NULL, row_version = row_version + 1, usermod = $3, fechmod = $2::timestamp WHERE codusr_usr = $1 AND deleted_at IS NOT NULL"))
+            .bind(id)
+            .bind(db::utc_now())
+            .bind(actor)
+            .execute(&self.pool)
+            .await?;
+        if DB::rows_affected(&res) == 0 {
+            return Ok(None);
+        }
+        self.get(id).await
+    }
+
+    // The user's sessions and refresh tokens go in the same transaction, so no token row outlives its user
+    async fn purge(&self, id: i32) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(&DB::sql("DELETE FROM refreshtoken WHERE UserID = $1")).bind(id).execute(&mut *tx).await?;
+        sqlx::query(&DB::sql("DELETE FROM usertoken WHERE UserID = $1")).bind(id).execute(&mut *tx).await?;
+        let res = sqlx::query(&DB::sql("DELETE FROM usuarios WHERE codusr_usr = $1")).bind(id).execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(DB::rows_affected(&res) > 0)
+    }
+
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(&DB::sql(&format!("SELECT {} FROM usuarios WHERE (nombre_usr = $1 OR email_usr = $1) AND deleted_at IS NULL", USER_COLUMNS)))
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(user)
+    }
+
+    async fn find_conflict(&self, username: Option<&str>, email: Option<&str>, except: Option<i32>) -> Result<Option<Conflict>> {
+        let kind: Option<i32> = sqlx::query_scalar(&DB::sql(
+            "SELECT CASE WHEN nombre_usr = $1 THEN 1 ELSE 2 END AS kind FROM usuarios \
+             WHERE (nombre_usr = $1 OR email_usr = $2) AND codusr_usr <> $3 ORDER BY kind LIMIT 1",
+        ))
+        .bind(username)
+        .bind(non_blank(email))
+        .bind(except.unwrap_or(0))
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(kind.map(|k| if k == 1 { Conflict::Username } else { Conflict::Email }))
+    }
+}
+
+pub struct SqlTokenStore<DB: Database> {
+    pool: Pool<DB>,
+}
+
+impl<DB: Database> SqlTokenStore<DB> {
+    pub fn new(pool: Pool<DB>) -> Self {
+        SqlTokenStore { pool }
+    }
+}
+
+// Column aliases keep the SQL Server spelling that the FromRow structs expect, which Postgres would otherwise
+// fold to lower case
+#[async_trait]
+impl<DB: Dialect> TokenStore for SqlTokenStore<DB>
+where
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> <DB as HasArguments<'q>>::Arguments: IntoArguments<'q, DB>,
+    for<'q> i32: Encode<'q, DB> + Type<DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+    for<'q> &'q str: Encode<'q, DB> + Type<DB>,
+    for<'q> Option<&'q str>: Encode<'q, DB>,
+    for<'r> SessionView: FromRow<'r, DB::Row>,
+    for<'r> RefreshRecord: FromRow<'r, DB::Row>,
+    for<'r> (i32,): FromRow<'r, DB::Row>,
+    for<'r> (i32, i32, String): FromRow<'r, DB::Row>,
+{
+    async fn insert_session(&self, user_id: i32, token_hash: &str, session: &NewSession, expires_at: &str) -> Result<i32> {
+        let (session_id,) = sqlx::query_as::<_, (i32,)>(&DB::sql(
+            "INSERT INTO usertoken (UserID, token, FamilyID, DeviceName, UserAgent, IpAddress, createdDate, LastSeen, expiredDate, expired, usercrea, usermod, fechcrea, fechmod) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7::timestamp, $7::timestamp, $8::timestamp, FALSE, $1, $1, $7::timestamp, $7::timestamp) RETURNING TokenID",
+        ))
+        .bind(user_id)
+        .bind(token_hash)
+        .bind(session.family_id.as_str())
+        .bind(session.device_name.as_deref())
+        .bind(session.user_agent.as_deref())
+        .bind(session.ip_address.as_deref())
+        .bind(db::utc_now())
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(session_id)
+    }
+
+    async fn renew_session(&self, user_id: i32, family_id: &str, token_hash: &str, expires_at: &str) -> Result<Option<i32>> {
+        let row = sqlx::query_as::<_, (i32,)>(&DB::sql(
+            "UPDATE usertoken SET token = $1, expiredDate = $2::timestamp, LastSeen = $3::timestamp, usermod = $5, fechmod = $3::timestamp \
+             WHERE FamilyID = $4 AND UserID = $5 AND expired = FALSE RETURNING TokenID",
+        ))
+        .bind(token_hash)
+        .bind(expires_at)
+        .bind(db::utc_now())
+        .bind(family_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|(session_id,)| session_id))
+    }
+
+    async fn find_session(&self, token_hash: &str) -> Result<Option<SessionRecord>> {
+        let row = sqlx::query_as::<_, (i32, i32, String)>(&DB::sql("SELECT TokenID, UserID, token FROM usertoken WHERE token = $1 AND expired = FALSE AND expiredDate > $2::timestamp"))
+            .bind(token_hash)
+            .bind(db::utc_now())
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|(session_id, user_id, token_hash)| SessionRecord { session_id, user_id, token_hash }))
+    }
+
+    async fn touch_session(&self, session_id: i32) -> Result<()> {
+        let now = chrono::Utc::now();
+        sqlx::query(&DB::sql("UPDATE usertoken SET LastSeen = $2::timestamp WHERE TokenID = $1 AND (LastSeen IS NULL OR LastSeen < $3::timestamp)"))
+            .bind(session_id)
+            .bind(db::utc_timestamp(now))
+            .bind(db::utc_timestamp(now - chrono::Duration::minutes(1)))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_sessions(&self, user_id: i32) -> Result<Vec<SessionView>> {
+        let sessions = sqlx::query_as::<_, SessionView>(&DB::sql(&format!(
+            "SELECT t.TokenID AS \"TokenID\", t.DeviceName AS \"DeviceName\", t.UserAgent AS \"UserAgent\", t.IpAddress AS \"IpAddress\", \
+             {} AS \"createdDate\", {} AS \"LastSeen\" \
+             FROM usertoken t \
+             WHERE t.UserID = $1 AND t.expired = FALSE \
+             AND (t.expiredDate > $2::timestamp OR EXISTS (SELECT 1 FROM refreshtoken r WHERE r.FamilyID = t.FamilyID AND r.revoked = FALSE AND r.used = FALSE AND r.expiredDate > $2::timestamp)) \
+             ORDER BY t.LastSeen DESC",
+            DB::timestamp_text("t.createdDate"),
+            DB::timestamp_text("t.LastSeen")
+        )))
+        .bind(user_id)
+        .bind(db::utc_now())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(sessions)
+    }
+
+    // Same effect as SP_LOGOUT, plus the refresh family
+    async fn revoke_session_by_token(&self, token_hash: &str) -> Result<()> {
+        let now = db::utc_now();
+        sqlx::query(&DB::sql("UPDATE refreshtoken SET revoked = TRUE, fechmod = $2::timestamp WHERE revoked = FALSE AND FamilyID IN (SELECT FamilyID FROM usertoken WHERE token = $1)"))
+            .bind(token_hash)
+            .bind(now.as_str())
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(&DB::sql("UPDATE usertoken SET expired = TRUE, fechmod = $2::timestamp WHERE token = $1 AND expired = FALSE"))
+            .bind(token_hash)
+            .bind(now.as_str())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn revoke_session(&self, user_id: i32, session_id: i32) -> Result<bool> {
+        let now = db::utc_now();
+        let res = sqlx::query(&DB::sql("UPDATE usertoken SET expired = TRUE, usermod = $2, fechmod = $3::timestamp WHERE TokenID = $1 AND UserID = $2"))
+            .bind(session_id)
+            .bind(user_id)
+            .bind(now.as_str())
+            .execute(&self.pool)
+            .await?;
+        if DB::rows_affected(&res) == 0 {
+            return Ok(false);
+        }
+        sqlx::query(&DB::sql("UPDATE refreshtoken SET revoked = TRUE, usermod = $2, fechmod = $3::timestamp WHERE revoked = FALSE AND FamilyID IN (SELECT FamilyID FROM usertoken WHERE TokenID = $1)"))
+            .bind(session_id)
+            .bind(user_id)
+            .bind(now.as_str())
+            .execute(&self.pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn revoke_all_sessions(&self, user_id: i32) -> Result<()> {
+        let now = db::utc_now();
+        sqlx::query(&DB::sql("UPDATE refreshtoken SET revoked = TRUE, usermod = $1, fechmod = $2::timestamp WHERE UserID = $1 AND revoked = FALSE"))
+            .bind(user_id)
+            .bind(now.as_str())
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(&DB::sql("UPDATE usertoken SET expired = TRUE, usermod = $1, fechmod = $2::timestamp WHERE UserID = $1 AND expired = FALSE"))
+            .bind(user_id)
+            .bind(now.as_str())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_refresh(&self, user_id: i32, family_id: &str, token_hash: &str, expires_at: &str) -> Result<()> {
+        sqlx::query(&DB::sql(
+            "INSERT INTO refreshtoken (UserID, FamilyID, token, createdDate, expiredDate, used, revoked, usercrea, usermod, fechcrea, fechmod) \
+             VALUES ($1, $2, $3, $5::timestamp, $4::timestamp, FALSE, FALSE, $1, $1, $5::timestamp, $5::timestamp)",
+        ))
+        .bind(user_id)
+        .bind(family_id)
+        .bind(token_hash)
+        .bind(expires_at)
+        .bind(db::utc_now())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn find_refresh(&self, token_hash: &str) -> Result<Option<RefreshRecord>> {
+        let row = sqlx::query_as::<_, RefreshRecord>(&DB::sql(&format!(
+            "SELECT RefreshID AS \"RefreshID\", UserID AS \"UserID\", FamilyID AS \"FamilyID\", token, used, revoked, {} AS \"expiredDate\" \
+             FROM refreshtoken WHERE token = $1",
+            DB::timestamp_text("expiredDate")
+        )))
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row)
+    }
+
+    async fn mark_refresh_used(&self, refresh_id: i32, user_id: i32) -> Result<bool> {
+        let res = sqlx::query(&DB::sql("UPDATE refreshtoken SET used = TRUE, usermod = $2, fechmod = $3::timestamp WHERE RefreshID = $1 AND used = FALSE AND revoked = FALSE"))
+            .bind(refresh_id)
+            .bind(user_id)
+            .bind(db::utc_now())
+            .execute(&self.pool)
+            .await?;
+        Ok(DB::rows_affected(&res) == 1)
+    }
+
+    async fn revoke_refresh_family(&self, family_id: &str) -> Result<()> {
+        sqlx::query(&DB::sql("UPDATE refreshtoken SET revoked = TRUE, fechmod = $2::timestamp WHERE FamilyID = $1 AND revoked = FALSE"))
+            .bind(family_id)
+            .bind(db::utc_now())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::db::Database;
+    use crate::migrations;
+    use crate::repository::sqlite;
+    use sqlx::Sqlite;
+
+    const LONG_AGO: &str = "2020-01-01 00:00:00";
+
+    async fn repository() -> (SqlUserRepository<Sqlite>, Pool<Sqlite>) {
+        let pool = sqlite::connect(&Settings::for_tests(), "sqlite::memory:").await.unwrap();
+        migrations::up(&Database::Sqlite(pool.clone())).await.unwrap();
+        (SqlUserRepository::new(pool.clone()), pool)
+    }
+
+    // (usercrea, usermod, fechcrea, fechmod)
+    async fn audit(pool: &Pool<Sqlite>, id: i32) -> (i32, i32, String, String) {
+        sqlx::query_as("SELECT usercrea, usermod, fechcrea, fechmod FROM usuarios WHERE codusr_usr = ?1").bind(id).fetch_one(pool).await.unwrap()
+    }
+
+    fn new_user(username: &str) -> CreateUser {
+        CreateUser { username: username.into(), email: None, password: "passw0rd-123".into() }
+    }
+
+    #[actix_web::test]
+    async fn updates_keep_the_creator_and_record_the_editor() {
+        let (users, pool) = repository().await;
+        let ana = users.create(new_user("ana"), 1, 7).await.unwrap();
+        let (creator, editor, _, _) = audit(&pool, ana.id).await;
+        assert_eq!((creator, editor), (7, 7));
+        // Backdated, so that rewriting either date would show even within the same second
+        sqlx::query("UPDATE usuarios SET fechcrea = ?1, fechmod = ?1 WHERE codusr_usr = ?2").bind(LONG_AGO).bind(ana.id).execute(&pool).await.unwrap();
+
+        let update = UpdateUser { email: Some(Some("ana@example.com".into())), ..Default::default() };
+        users.update(ana.id, update, 9, None).await.unwrap().unwrap();
+        let (creator, editor, created_at, modified_at) = audit(&pool, ana.id).await;
+        assert_eq!((creator, editor, created_at.as_str()), (7, 9, LONG_AGO));
+        assert!(modified_at.as_str() > LONG_AGO);
+
+        users.delete(ana.id, 11).await.unwrap();
+        assert_eq!(audit(&pool, ana.id).await.1, 11);
+        users.restore(ana.id, 12).await.unwrap().unwrap();
+        let (creator, editor, created_at, _) = audit(&pool, ana.id).await;
+        assert_eq!((creator, editor, created_at.as_str()), (7, 12, LONG_AGO));
+    }
+
+    #[actix_web::test]
+    async fn bulk_creates_record_the_importer() {
+        let (users, pool) = repository().await;
+        let created = users.create_many(vec![(new_user("bea"), 1), (new_user("carla"), 1)], 3).await.unwrap();
+        for user in created {
+            let (creator, editor, created_at, modified_at) = audit(&pool, user.id).await;
+            assert_eq!((creator, editor), (3, 3));
+            assert_eq!(created_at, modified_at);
+        }
+    }
+}
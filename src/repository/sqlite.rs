@@ -0,0 +1,68 @@
+use super::sql::{Dialect, SqlTokenStore, SqlUserRepository};
+use crate::config::Settings;
+use anyhow::Result;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteQueryResult};
+use sqlx::{Pool, Sqlite};
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Tables come from migrations/sqlite. Timestamps are TEXT in the db::utc_timestamp format,
+// which sorts chronologically, and flags are 0/1 integers (TRUE and FALSE are the same numbers).
+pub type SqliteUserRepository = SqlUserRepository<Sqlite>;
+pub type SqliteTokenStore = SqlTokenStore<Sqlite>;
+
+// Opens DATABASE_URL, creating the file if it does not exist. The tables come from `backend migrate up`.
+pub async fn connect(settings: &Settings, url: &str) -> Result<Pool<Sqlite>> {
+    let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+    // Each connection to sqlite::memory: would see its own empty database
+    let max_connections = if url.contains(":memory:") { 1 } else { settings.db.max_connections };
+    let pool = SqlitePoolOptions::new()
+        .min_connections(settings.db.min_connections.min(max_connections))
+        .max_connections(max_connections)
+        .acquire_timeout(Duration::from_secs(settings.db.acquire_timeout_secs))
+        .connect_with(options)
+        .await?;
+    Ok(pool)
+}
+
+impl Dialect for Sqlite {
+    // $N becomes ?N (SQLite numbers `$name` parameters by first use, not by N) and casts are dropped.
+    // LIKE already ignores ASCII case here.
+    fn sql(query: &str) -> Cow<'_, str> {
+        let mut out = String::with_capacity(query.len());
+        let mut chars = query.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '$' => out.push('?'),
+                ':' if chars.peek() == Some(&':') => {
+                    chars.next();
+                    while chars.next_if(|c| c.is_ascii_alphanumeric()).is_some() {}
+                }
+                _ => out.push(c),
+            }
+        }
+        Cow::Owned(out.replace(" ILIKE ", " LIKE "))
+    }
+
+    fn timestamp_text(column: &str) -> String {
+        column.to_string()
+    }
+
+    fn rows_affected(result: &SqliteQueryResult) -> u64 {
+        result.rows_affected()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sql_is_rewritten_for_sqlite() {
+        assert_eq!(
+            Sqlite::sql("UPDATE t SET a = $2::timestamp WHERE id = $10 AND ($3::text IS NULL OR b ILIKE $3 ESCAPE '\\') AND c = FALSE"),
+            "UPDATE t SET a = ?2 WHERE id = ?10 AND (?3 IS NULL OR b LIKE ?3 ESCAPE '\\') AND c = FALSE"
+        );
+    }
+}
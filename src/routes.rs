@@ -0,0 +1,34 @@
+use actix_web::middleware::from_fn;
+use actix_web::web;
+use crate::error::ApiError;
+use crate::{auth, handlers, middleware};
+
+// Shared by main and the HTTP tests, so both serve exactly the same routes
+pub fn configure(app: &mut web::ServiceConfig) {
+    app
+        // Malformed bodies and query strings get the same problem+json as handler errors; a non-numeric id is a 404
+        .app_data(web::JsonConfig::default().error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()))
+        .app_data(web::QueryConfig::default().error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()))
+        .app_data(web::PathConfig::default().error_handler(|_, _| ApiError::NotFound.into()))
+        // Public routes: login, self-registration, logout (which only needs the bearer token), refresh and the signup availability check
+        .route("/login", web::post().to(handlers::login))
+        // Anyone may sign up, but a signed-in caller is recorded as the creator
+        .route("/users", web::post().to(handlers::create_user).wrap(from_fn(middleware::optional_auth)))
+        .route("/logout", web::post().to(handlers::logout))
+        .route("/token/refresh", web::post().to(handlers::refresh_token))
+        // Before /users/{id}, which would otherwise match it
+        .route("/users/availability", web::get().to(handlers::availability))
+        // Everything else requires a valid bearer token
+        .route("/users", web::get().to(handlers::list_users).wrap(from_fn(middleware::require_role(auth::ROLE_ADMIN))).wrap(from_fn(middleware::require_auth)))
+        .route("/users/import", web::post().to(handlers::import_users).wrap(from_fn(middleware::require_role(auth::ROLE_ADMIN))).wrap(from_fn(middleware::require_auth)))
+        .route("/users/export", web::get().to(handlers::export_users).wrap(from_fn(middleware::require_role(auth::ROLE_ADMIN))).wrap(from_fn(middleware::require_auth)))
+        .route("/users/{id}", web::get().to(handlers::get_user).wrap(from_fn(middleware::require_auth)))
+        .route("/users/{id}", web::put().to(handlers::update_user).wrap(from_fn(middleware::require_auth)))
+        .route("/users/{id}", web::patch().to(handlers::patch_user).wrap(from_fn(middleware::require_auth)))
+        .route("/users/{id}", web::delete().to(handlers::delete_user).wrap(from_fn(middleware::require_auth)))
+        .route("/users/{id}/restore", web::post().to(handlers::restore_user).wrap(from_fn(middleware::require_role(auth::ROLE_ADMIN))).wrap(from_fn(middleware::require_auth)))
+        .route("/logout/all", web::post().to(handlers::logout_all).wrap(from_fn(middleware::require_auth)))
+        .route("/me/sessions", web::get().to(handlers::list_sessions).wrap(from_fn(middleware::require_auth)))
+        .route("/me/sessions/{id}", web::delete().to(handlers::revoke_session).wrap(from_fn(middleware::require_auth)))
+        .route("/load_concurrent", web::get().to(handlers::load_concurrent).wrap(from_fn(middleware::require_role(auth::ROLE_ADMIN))).wrap(from_fn(middleware::require_auth)));
+}
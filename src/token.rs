@@ -1,137 +1,94 @@
-use scrypt::{Params, scrypt};
-use aes::Aes256;
-use ctr::Ctr128BE;
-use aes::cipher::{KeyIvInit, StreamCipher};
 use rand::rngs::OsRng;
 use rand::RngCore;
-use hex;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 use actix_web::HttpRequest;
 use anyhow::Result;
-
-pub struct Encryption {
-    iv: [u8; 16],
-    key: [u8; 32],
+use crate::auth::{self, AuthUser};
+use crate::config::{Settings, TokenMode};
+use crate::db::utc_timestamp;
+use crate::models::User;
+use crate::repository::{TokenStore, UserRepository};
+
+// 256 bits from the OS CSPRNG, hex encoded. Only the SHA-256 of this value is ever stored.
+pub fn generate_opaque_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
 }
 
-impl Encryption {
-    pub fn new() -> Self {
-        let mut iv = [0u8; 16];
-        OsRng.fill_bytes(&mut iv);
-        Encryption { iv, key: [0u8; 32] }
-    }
-
-    pub fn initialize(&mut self, password: &str) -> Result<()> {
-        // scrypt with salt 'salt' (to match TS)
-        let params = Params::recommended();
-        let salt = b"salt";
-        scrypt(password.as_bytes(), salt, &params, &mut self.key)?;
-        Ok(())
-    }
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
 
-    pub fn encryption(&self, text: &str) -> Result<String> {
-        type Aes256Ctr = Ctr128BE<Aes256>;
-        let mut cipher = Aes256Ctr::new((&self.key).into(), (&self.iv).into());
-        let mut buffer = text.as_bytes().to_vec();
-        cipher.apply_keystream(&mut buffer);
-        let encrypted_hex = hex::encode(buffer);
-        Ok(encrypted_hex.chars().take(100).collect())
-    }
+pub fn hashes_match(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
 
-    // decipher removed because tokens are single-direction in current flow
+// Each login is its own usertoken row; the refresh token family issued with it shares FamilyID
+pub struct NewSession {
+    pub family_id: String,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
 }
 
 pub struct TokenService;
 
 impl TokenService {
-    #[allow(dead_code)]
-    pub async fn generate_token(pool: &sqlx::Pool<sqlx::Mssql>, text_to_encrypt: &str, expired: bool, _time: Option<i64>, secret: &str) -> Result<String> {
-        let mut enc = Encryption::new();
-        enc.initialize(secret)?;
-        let token = enc.encryption(&text_to_encrypt.repeat(20))?;
-        // Determine expiredDate (DB expects a non-null datetime)
-        let minutes = _time.unwrap_or(10);
-        let expired_date = chrono::Utc::now() + chrono::Duration::minutes(minutes);
-        let expired_date_str = expired_date.format("%Y-%m-%d %H:%M:%S").to_string();
-        let expired_flag: i32 = if expired { 1 } else { 0 };
-
-        // Parse user id from text_to_encrypt (handlers pass user.id.to_string())
-        let user_id_parsed: i32 = text_to_encrypt.parse::<i32>().unwrap_or(0);
-
-        // Upsert token: update if exists, otherwise insert and set metadata fields (usercrea/usermod/fechcrea/fechmod)
-        let upsert_sql = r#"
-            IF EXISTS (SELECT 1 FROM usertoken WHERE UserID = @p1)
-            BEGIN
-                UPDATE usertoken
-                SET token = @p2,
-                    createdDate = GETDATE(),
-                    expiredDate = @p3,
-                    expired = @p4,
-                    usermod = @p5,
-                    fechmod = GETDATE()
-                WHERE UserID = @p1;
-            END
-            ELSE
-            BEGIN
-                INSERT INTO usertoken (UserID, token, createdDate, expiredDate, expired, usercrea, usermod, fechcrea, fechmod)
-                VALUES (@p1, @p2, GETDATE(), @p3, @p4, @p5, @p5, GETDATE(), GETDATE());
-            END
-        "#;
-
-        let _ = sqlx::query(upsert_sql)
-            .bind(user_id_parsed)
-            .bind(token.clone())
-            .bind(expired_date_str)
-            .bind(expired_flag)
-            .bind(0i32) // usermod/usercrea default 0
-            .execute(pool)
-            .await?;
-        Ok(token)
+    // Starts a new session and returns its access token and id, in whichever format TOKEN_MODE selects
+    pub async fn issue_access_token(tokens: &dyn TokenStore, cfg: &Settings, user: &User, session: &NewSession) -> Result<(String, i32)> {
+        match cfg.token_mode {
+            TokenMode::Jwt => {
+                let jti = uuid::Uuid::new_v4().to_string();
+                let session_id = tokens.insert_session(user.id, &hash_token(&jti), session, &expires_in(cfg.access_token_ttl_minutes)).await?;
+                Ok((auth::create_token(user, cfg, session_id, jti)?, session_id))
+            }
+            TokenMode::Opaque => Self::generate_token(tokens, user.id, Some(cfg.access_token_ttl_minutes), session).await,
+        }
+    }
+
+    pub async fn generate_token(tokens: &dyn TokenStore, user_id: i32, time: Option<i64>, session: &NewSession) -> Result<(String, i32)> {
+        let token = generate_opaque_token();
+        let session_id = tokens.insert_session(user_id, &hash_token(&token), session, &expires_in(time.unwrap_or(10))).await?;
+        Ok((token, session_id))
     }
 
-    #[allow(dead_code)]
-    pub async fn register_token(pool: &sqlx::Pool<sqlx::Mssql>, user_id: i64, token: &str) -> Result<sqlx::mssql::MssqlRow> {
-        let expired_date = chrono::Utc::now() + chrono::Duration::minutes(10);
-        let expired_date_str = expired_date.format("%Y-%m-%d %H:%M:%S").to_string();
-        let upsert_sql = r#"
-            IF EXISTS (SELECT 1 FROM usertoken WHERE UserID = @p1)
-            BEGIN
-                UPDATE usertoken
-                SET token = @p2,
-                    createdDate = GETDATE(),
-                    expiredDate = @p3,
-                    expired = @p4,
-                    usermod = @p5,
-                    fechmod = GETDATE()
-                WHERE UserID = @p1;
-                SELECT 1 as registered;
-            END
-            ELSE
-            BEGIN
-                INSERT INTO usertoken (UserID, token, createdDate, expiredDate, expired, usercrea, usermod, fechcrea, fechmod)
-                VALUES (@p1, @p2, GETDATE(), @p3, @p4, @p5, @p5, GETDATE(), GETDATE());
-                SELECT 1 as registered;
-            END
-        "#;
-
-        let row = sqlx::query(upsert_sql)
-            .bind(user_id as i32)
-            .bind(token)
-            .bind(expired_date_str)
-            .bind(0i32)
-            .bind(0i32)
-            .fetch_one(pool)
-            .await?;
-        Ok(row)
+    // Issues a fresh access token for the session bound to a refresh token family.
+    // Returns None when that session has been revoked in the meantime.
+    pub async fn renew_session(tokens: &dyn TokenStore, cfg: &Settings, user: &User, family_id: &str) -> Result<Option<String>> {
+        let (token, stored) = match cfg.token_mode {
+            TokenMode::Jwt => (None, uuid::Uuid::new_v4().to_string()),
+            TokenMode::Opaque => {
+                let t = generate_opaque_token();
+                (Some(t.clone()), t)
+            }
+        };
+        let session_id = tokens.renew_session(user.id, family_id, &hash_token(&stored), &expires_in(cfg.access_token_ttl_minutes)).await?;
+        match (session_id, token) {
+            (None, _) => Ok(None),
+            (Some(_), Some(token)) => Ok(Some(token)),
+            // JWT mode: what was stored is the jti
+            (Some(session_id), None) => Ok(Some(auth::create_token(user, cfg, session_id, stored)?)),
+        }
     }
 
-    #[allow(dead_code)]
-    pub async fn validated_token(pool: &sqlx::Pool<sqlx::Mssql>, token: &str) -> Result<Vec<sqlx::mssql::MssqlRow>> {
-        let sql = format!("EXEC SP_VALIDATE_TOKEN @token='{}'", token);
-        let rows = sqlx::query(&sql).fetch_all(pool).await?;
-        Ok(rows)
+    // Resolves an opaque token to its session owner; the user is re-read so role changes apply immediately
+    pub async fn authenticate(tokens: &dyn TokenStore, users: &dyn UserRepository, cfg: &Settings, token: &str) -> Result<Option<AuthUser>> {
+        let token_hash = hash_token(token);
+        // The lookup is by hash; re-check the stored value without short-circuiting on the first differing byte
+        let session = match tokens.find_session(&token_hash).await? {
+            Some(s) if hashes_match(&s.token_hash, &token_hash) => s,
+            _ => return Ok(None),
+        };
+        let user = match users.get(session.user_id).await? {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+        tokens.touch_session(session.session_id).await?;
+        Ok(Some(AuthUser { id: user.id, username: user.username, session_id: session.session_id, role: auth::role_for_profile(user.profile, cfg).into() }))
     }
 
-    #[allow(dead_code)]
     pub fn extract_token_from_header(req: &HttpRequest) -> Option<String> {
         let header = req.headers().get("authorization")?.to_str().ok()?;
         let mut parts = header.split_whitespace();
@@ -140,17 +97,96 @@ impl TokenService {
         if typ.eq_ignore_ascii_case("Bearer") { Some(token.to_string()) } else { None }
     }
 
-    #[allow(dead_code)]
-    pub async fn get_user_token(pool: &sqlx::Pool<sqlx::Mssql>, token: &str) -> Result<sqlx::mssql::MssqlRow> {
-        let sql = format!("EXEC SP_GET_USER_TOKEN @token='{}'", token);
-        let row = sqlx::query(&sql).fetch_one(pool).await?;
-        Ok(row)
+    // Revoking an unknown or already revoked token is not an error, so logout stays idempotent.
+    // The refresh token family of the session goes with it.
+    pub async fn revoke_token(tokens: &dyn TokenStore, raw_token: &str) -> Result<()> {
+        tokens.revoke_session_by_token(&hash_token(raw_token)).await
+    }
+}
+
+fn expires_in(minutes: i64) -> String {
+    utc_timestamp(chrono::Utc::now() + chrono::Duration::minutes(minutes))
+}
+
+// Classic injection payloads, shared by every test that feeds caller-controlled tokens to the stores
+#[cfg(test)]
+pub(crate) const HOSTILE_TOKENS: &[&str] = &[
+    "' OR '1'='1",
+    "abc'; DROP TABLE usertoken; --",
+    "x'--",
+    "x' /* comment */ OR 1=1 /*",
+    "'; EXEC xp_cmdshell 'dir'; --",
+    "''''",
+    "1; SELECT codusr_usr, contrasena_usr FROM usuarios",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::migrations;
+    use crate::models::CreateUser;
+    use crate::repository::{sqlite, SqliteTokenStore, SqliteUserRepository};
+    use actix_web::test::TestRequest;
+
+    fn session(family_id: &str) -> NewSession {
+        NewSession { family_id: family_id.into(), device_name: None, user_agent: None, ip_address: None }
+    }
+
+    // Every entry point that takes a caller-supplied token finds nothing, changes nothing and leaves the tables alone
+    #[actix_web::test]
+    async fn hostile_tokens_match_nothing_in_the_sql_store() {
+        let cfg = Settings::for_tests();
+        let pool = sqlite::connect(&cfg, "sqlite::memory:").await.unwrap();
+        migrations::up(&Database::Sqlite(pool.clone())).await.unwrap();
+        let (users, tokens) = (SqliteUserRepository::new(pool.clone()), SqliteTokenStore::new(pool.clone()));
+        let user = users.create(CreateUser { username: "ana".into(), email: None, password: "passw0rd-123".into() }, 1, 0).await.unwrap();
+        let (token, _) = TokenService::issue_access_token(&tokens, &cfg, &user, &session("family-a")).await.unwrap();
+
+        for hostile in HOSTILE_TOKENS {
+            assert!(TokenService::authenticate(&tokens, &users, &cfg, hostile).await.unwrap().is_none());
+            TokenService::revoke_token(&tokens, hostile).await.unwrap();
+            assert!(TokenService::renew_session(&tokens, &cfg, &user, hostile).await.unwrap().is_none());
+        }
+
+        let (rows, live) = sqlx::query_as::<_, (i64, i64)>("SELECT COUNT(*), SUM(expired = 0) FROM usertoken").fetch_one(&pool).await.unwrap();
+        assert_eq!((rows, live), (1, 1));
+        let session = TokenService::authenticate(&tokens, &users, &cfg, &token).await.unwrap().unwrap();
+        assert_eq!(session.id, user.id);
+    }
+
+    #[test]
+    fn opaque_tokens_have_256_bits_and_do_not_repeat() {
+        let tokens: Vec<String> = (0..64).map(|_| generate_opaque_token()).collect();
+        for t in &tokens {
+            assert_eq!(t.len(), 64);
+            assert_eq!(hex::decode(t).unwrap().len(), 32);
+        }
+        let unique: std::collections::HashSet<&String> = tokens.iter().collect();
+        assert_eq!(unique.len(), tokens.len());
+        // the old scheme made every token of a user share a long prefix
+        assert!(tokens.windows(2).all(|w| w[0][..8] != w[1][..8]));
+    }
+
+    #[test]
+    fn hash_token_is_sha256_hex() {
+        assert_eq!(hash_token("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_ne!(hash_token("abc"), hash_token("abd"));
+    }
+
+    #[test]
+    fn hashes_match_compares_whole_value() {
+        let h = hash_token("token");
+        assert!(hashes_match(&h, &hash_token("token")));
+        assert!(!hashes_match(&h, &hash_token("other")));
+        assert!(!hashes_match(&h, &h[..63]));
     }
 
-    #[allow(dead_code)]
-    pub async fn revoke_token(pool: &sqlx::Pool<sqlx::Mssql>, raw_token: &str) -> Result<sqlx::mssql::MssqlRow> {
-        let sql = format!("EXEC SP_LOGOUT @token='{}'", raw_token);
-        let row = sqlx::query(&sql).fetch_one(pool).await?;
-        Ok(row)
+    #[test]
+    fn extract_token_keeps_hostile_token_verbatim() {
+        for token in HOSTILE_TOKENS.iter().filter(|t| !t.contains(' ')) {
+            let req = TestRequest::default().insert_header(("Authorization", format!("Bearer {}", token))).to_http_request();
+            assert_eq!(TokenService::extract_token_from_header(&req).as_deref(), Some(*token));
+        }
     }
 }